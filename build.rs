@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto");
+    #[cfg(feature = "grpc")]
+    {
+        // a protoc binary is vendored, so building doesn't depend on one being installed
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_with_config(config, &["proto/ipwned.proto"], &["proto"])
+            .unwrap();
+    }
+}
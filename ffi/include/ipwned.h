@@ -0,0 +1,121 @@
+#ifndef IPWNED_H
+#define IPWNED_H
+
+/* generated by cbindgen from ffi/src/lib.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ An opened filter, created by `ipwned_filter_open` and released with `ipwned_filter_close`.
+ */
+typedef struct IpwnedFilter IpwnedFilter;
+
+/*
+ Size and error rate of a filter.
+ */
+typedef struct IpwnedFilterInfo {
+  /*
+   number of hashes in the filter
+   */
+  uint64_t entries;
+  /*
+   maximum number of hashes the filter can hold
+   */
+  uint64_t capacity;
+  /*
+   false positive rate the filter was created for
+   */
+  double max_error_rate;
+  /*
+   false positive rate with the current number of entries
+   */
+  double current_error_rate;
+  /*
+   memory used by the filter in bytes
+   */
+  size_t memory_usage;
+} IpwnedFilterInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Opens the filter file at `path`, a NUL terminated UTF-8 string.
+
+ Returns NULL on error, see `ipwned_last_error`.
+
+ # Safety
+ `path` must be NULL or point to a NUL terminated string.
+ */
+struct IpwnedFilter *ipwned_filter_open(const char *path);
+
+/*
+ Checks the raw 20 byte SHA1 hash `sha1`.
+
+ Returns 1 if the hash is in the filter, 0 if it is not and -1 on invalid arguments.
+
+ # Safety
+ `filter` must be NULL or returned by `ipwned_filter_open`, `sha1` must be NULL or point to 20 bytes.
+ */
+int ipwned_filter_contains_sha1(const struct IpwnedFilter *filter,
+                                const uint8_t *sha1);
+
+/*
+ Checks the SHA1 hash given as NUL terminated string of 40 hex characters.
+
+ Returns 1 if the hash is in the filter, 0 if it is not and -1 on invalid arguments.
+
+ # Safety
+ `filter` must be NULL or returned by `ipwned_filter_open`, `hex` must be NULL or point to a NUL
+ terminated string.
+ */
+int ipwned_filter_contains_sha1_hex(const struct IpwnedFilter *filter, const char *hex);
+
+/*
+ Checks the password given as `len` bytes, which are hashed as is. HIBP hashes UTF-8 encoded passwords.
+
+ Returns 1 if the password is in the filter, 0 if it is not and -1 on invalid arguments.
+
+ # Safety
+ `filter` must be NULL or returned by `ipwned_filter_open`, `password` must be NULL or point to `len`
+ bytes.
+ */
+int ipwned_filter_contains_password(const struct IpwnedFilter *filter,
+                                    const uint8_t *password,
+                                    size_t len);
+
+/*
+ Writes the size and error rate of the filter to `info`.
+
+ Returns 0 on success and -1 on invalid arguments.
+
+ # Safety
+ `filter` must be NULL or returned by `ipwned_filter_open`, `info` must be NULL or writable.
+ */
+int ipwned_filter_info(const struct IpwnedFilter *filter, struct IpwnedFilterInfo *info);
+
+/*
+ Releases a filter returned by `ipwned_filter_open`, NULL is ignored.
+
+ # Safety
+ `filter` must not be used after this call.
+ */
+void ipwned_filter_close(struct IpwnedFilter *filter);
+
+/*
+ Returns the message of the last error on the calling thread, or NULL if there was none.
+
+ The string is owned by the library and valid until the next failing call on the same thread.
+ */
+const char *ipwned_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* IPWNED_H */
@@ -0,0 +1,333 @@
+//! C API for querying a filter written by `ipwned-builder` in-process.
+//!
+//! The generated header is `ffi/include/ipwned.h`. A filter is read-only once opened, so all query functions
+//! can be called from multiple threads at the same time on the same filter.
+
+use ipwned_localdb::PwnedFilter;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg).unwrap_or_else(|_| c"invalid error message".to_owned());
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+fn to_result(found: bool) -> c_int {
+    if found { 1 } else { 0 }
+}
+
+/// An opened filter, created by `ipwned_filter_open` and released with `ipwned_filter_close`.
+pub struct IpwnedFilter(PwnedFilter);
+
+/// Size and error rate of a filter.
+#[repr(C)]
+pub struct IpwnedFilterInfo {
+    /// number of hashes in the filter
+    pub entries: u64,
+    /// maximum number of hashes the filter can hold
+    pub capacity: u64,
+    /// false positive rate the filter was created for
+    pub max_error_rate: f64,
+    /// false positive rate with the current number of entries
+    pub current_error_rate: f64,
+    /// memory used by the filter in bytes
+    pub memory_usage: usize,
+}
+
+/// Opens the filter file at `path`, a NUL terminated UTF-8 string.
+///
+/// Returns NULL on error, see `ipwned_last_error`.
+///
+/// # Safety
+/// `path` must be NULL or point to a NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ipwned_filter_open(path: *const c_char) -> *mut IpwnedFilter {
+    if path.is_null() {
+        set_last_error("path is NULL".into());
+        return ptr::null_mut();
+    }
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(x) => x,
+        Err(_) => {
+            set_last_error("path is not valid UTF-8".into());
+            return ptr::null_mut();
+        }
+    };
+    match PwnedFilter::open(path) {
+        Ok(filter) => Box::into_raw(Box::new(IpwnedFilter(filter))),
+        Err(e) => {
+            set_last_error(format!("unable to open filter {}: {}", path, e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Checks the raw 20 byte SHA1 hash `sha1`.
+///
+/// Returns 1 if the hash is in the filter, 0 if it is not and -1 on invalid arguments.
+///
+/// # Safety
+/// `filter` must be NULL or returned by `ipwned_filter_open`, `sha1` must be NULL or point to 20 bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ipwned_filter_contains_sha1(
+    filter: *const IpwnedFilter,
+    sha1: *const u8,
+) -> c_int {
+    if filter.is_null() || sha1.is_null() {
+        set_last_error("filter or sha1 is NULL".into());
+        return -1;
+    }
+    let (filter, sha1) = unsafe { (&*filter, &*(sha1 as *const [u8; 20])) };
+    to_result(filter.0.contains(sha1))
+}
+
+/// Checks the SHA1 hash given as NUL terminated string of 40 hex characters.
+///
+/// Returns 1 if the hash is in the filter, 0 if it is not and -1 on invalid arguments.
+///
+/// # Safety
+/// `filter` must be NULL or returned by `ipwned_filter_open`, `hex` must be NULL or point to a NUL
+/// terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ipwned_filter_contains_sha1_hex(
+    filter: *const IpwnedFilter,
+    hex: *const c_char,
+) -> c_int {
+    if filter.is_null() || hex.is_null() {
+        set_last_error("filter or hex is NULL".into());
+        return -1;
+    }
+    let (filter, hex) = unsafe { (&*filter, CStr::from_ptr(hex)) };
+    match hex.to_str().ok().and_then(|x| filter.0.contains_hex(x)) {
+        Some(found) => to_result(found),
+        None => {
+            set_last_error("hash is not 40 hex characters".into());
+            -1
+        }
+    }
+}
+
+/// Checks the password given as `len` bytes, which are hashed as is. HIBP hashes UTF-8 encoded passwords.
+///
+/// Returns 1 if the password is in the filter, 0 if it is not and -1 on invalid arguments.
+///
+/// # Safety
+/// `filter` must be NULL or returned by `ipwned_filter_open`, `password` must be NULL or point to `len`
+/// bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ipwned_filter_contains_password(
+    filter: *const IpwnedFilter,
+    password: *const u8,
+    len: usize,
+) -> c_int {
+    if filter.is_null() || (password.is_null() && len > 0) {
+        set_last_error("filter or password is NULL".into());
+        return -1;
+    }
+    let filter = unsafe { &*filter };
+    let password = match len {
+        0 => &[][..],
+        _ => unsafe { std::slice::from_raw_parts(password, len) },
+    };
+    to_result(filter.0.contains_password(password))
+}
+
+/// Writes the size and error rate of the filter to `info`.
+///
+/// Returns 0 on success and -1 on invalid arguments.
+///
+/// # Safety
+/// `filter` must be NULL or returned by `ipwned_filter_open`, `info` must be NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ipwned_filter_info(
+    filter: *const IpwnedFilter,
+    info: *mut IpwnedFilterInfo,
+) -> c_int {
+    if filter.is_null() || info.is_null() {
+        set_last_error("filter or info is NULL".into());
+        return -1;
+    }
+    let filter = unsafe { &*filter }.0.info();
+    let stats = IpwnedFilterInfo {
+        entries: filter.entries,
+        capacity: filter.capacity,
+        max_error_rate: filter.max_error_rate,
+        current_error_rate: filter.current_error_rate,
+        memory_usage: filter.memory_usage,
+    };
+    unsafe { info.write(stats) };
+    0
+}
+
+/// Releases a filter returned by `ipwned_filter_open`, NULL is ignored.
+///
+/// # Safety
+/// `filter` must not be used after this call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ipwned_filter_close(filter: *mut IpwnedFilter) {
+    if !filter.is_null() {
+        drop(unsafe { Box::from_raw(filter) });
+    }
+}
+
+/// Returns the message of the last error on the calling thread, or NULL if there was none.
+///
+/// The string is owned by the library and valid until the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn ipwned_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |x| x.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::path::PathBuf;
+
+    /// filter file with the SHA1 hash of "password", copied with a higher error rate like `export-client` does
+    fn filter_file(name: &str) -> PathBuf {
+        let mut filter = qfilter::Filter::new(64, 1e-6).unwrap();
+        filter.insert(&Sha1::digest("password")[..]).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&filter, &mut cbor).unwrap();
+        let filter = PwnedFilter::from_reader(&cbor[..])
+            .unwrap()
+            .with_error_rate(1e-4)
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("ipwned_ffi_{}_test_{}", name, std::process::id()));
+        ciborium::into_writer(&filter, std::fs::File::create(&path).unwrap()).unwrap();
+        path
+    }
+
+    fn last_error() -> Option<String> {
+        let error = ipwned_last_error();
+        (!error.is_null()).then(|| {
+            unsafe { CStr::from_ptr(error) }
+                .to_str()
+                .unwrap()
+                .to_string()
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = filter_file("round_trip");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let filter = unsafe { ipwned_filter_open(c_path.as_ptr()) };
+        std::fs::remove_file(&path).unwrap();
+        assert!(!filter.is_null());
+
+        let pwned: [u8; 20] = Sha1::digest("password").into();
+        let hex = CString::new(format!("{:x}", Sha1::digest("password"))).unwrap();
+        unsafe {
+            assert_eq!(ipwned_filter_contains_sha1(filter, pwned.as_ptr()), 1);
+            assert_eq!(ipwned_filter_contains_sha1(filter, [0u8; 20].as_ptr()), 0);
+            assert_eq!(ipwned_filter_contains_sha1_hex(filter, hex.as_ptr()), 1);
+            assert_eq!(
+                ipwned_filter_contains_password(filter, b"password".as_ptr(), 8),
+                1
+            );
+            assert_eq!(
+                ipwned_filter_contains_password(filter, b"not pwned".as_ptr(), 9),
+                0
+            );
+            assert_eq!(ipwned_filter_contains_password(filter, ptr::null(), 0), 0);
+        }
+        let invalid = c"5baa61e4";
+        assert_eq!(
+            unsafe { ipwned_filter_contains_sha1_hex(filter, invalid.as_ptr()) },
+            -1
+        );
+        assert_eq!(last_error().unwrap(), "hash is not 40 hex characters");
+
+        let mut info = IpwnedFilterInfo {
+            entries: 0,
+            capacity: 0,
+            max_error_rate: 0.,
+            current_error_rate: 0.,
+            memory_usage: 0,
+        };
+        assert_eq!(unsafe { ipwned_filter_info(filter, &mut info) }, 0);
+        let expected = unsafe { &*filter }.0.info();
+        assert_eq!(info.entries, 1);
+        assert_eq!(info.max_error_rate, expected.max_error_rate);
+        assert!(info.max_error_rate > 1e-6);
+        assert_eq!(info.memory_usage, expected.memory_usage);
+        unsafe { ipwned_filter_close(filter) };
+    }
+
+    #[test]
+    fn null_arguments() {
+        assert!(unsafe { ipwned_filter_open(ptr::null()) }.is_null());
+        assert_eq!(last_error().unwrap(), "path is NULL");
+        let sha1 = [0u8; 20];
+        unsafe {
+            assert_eq!(ipwned_filter_contains_sha1(ptr::null(), sha1.as_ptr()), -1);
+            assert_eq!(
+                ipwned_filter_contains_sha1_hex(ptr::null(), c"".as_ptr()),
+                -1
+            );
+            assert_eq!(
+                ipwned_filter_contains_password(ptr::null(), ptr::null(), 0),
+                -1
+            );
+            assert_eq!(ipwned_filter_info(ptr::null(), ptr::null_mut()), -1);
+            ipwned_filter_close(ptr::null_mut());
+        }
+        assert_eq!(last_error().unwrap(), "filter or info is NULL");
+
+        let path = filter_file("null_arguments");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let filter = unsafe { ipwned_filter_open(c_path.as_ptr()) };
+        std::fs::remove_file(&path).unwrap();
+        unsafe {
+            assert_eq!(ipwned_filter_contains_sha1(filter, ptr::null()), -1);
+            assert_eq!(last_error().unwrap(), "filter or sha1 is NULL");
+            assert_eq!(ipwned_filter_contains_sha1_hex(filter, ptr::null()), -1);
+            assert_eq!(last_error().unwrap(), "filter or hex is NULL");
+            assert_eq!(ipwned_filter_contains_password(filter, ptr::null(), 1), -1);
+            assert_eq!(last_error().unwrap(), "filter or password is NULL");
+            assert_eq!(ipwned_filter_info(filter, ptr::null_mut()), -1);
+            ipwned_filter_close(filter);
+        }
+    }
+
+    #[test]
+    fn last_error_per_thread() {
+        let missing = c"/nonexistent/ipwned_qfilter.cbor";
+        assert!(unsafe { ipwned_filter_open(missing.as_ptr()) }.is_null());
+        let error = last_error().unwrap();
+        assert!(
+            error.starts_with("unable to open filter /nonexistent/ipwned_qfilter.cbor: "),
+            "{}",
+            error
+        );
+        std::thread::spawn(|| {
+            assert_eq!(last_error(), None);
+            assert!(unsafe { ipwned_filter_open(ptr::null()) }.is_null());
+            assert_eq!(last_error().unwrap(), "path is NULL");
+        })
+        .join()
+        .unwrap();
+        assert_eq!(last_error().unwrap(), error);
+
+        // successful calls keep the last error
+        let path = filter_file("last_error");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let filter = unsafe { ipwned_filter_open(c_path.as_ptr()) };
+        std::fs::remove_file(&path).unwrap();
+        assert!(!filter.is_null());
+        assert_eq!(last_error().unwrap(), error);
+        unsafe { ipwned_filter_close(filter) };
+
+        let invalid = c"\xff";
+        assert!(unsafe { ipwned_filter_open(invalid.as_ptr()) }.is_null());
+        assert_eq!(last_error().unwrap(), "path is not valid UTF-8");
+    }
+}
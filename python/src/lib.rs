@@ -0,0 +1,254 @@
+//! Python module `ipwned` for querying a filter written by `ipwned-builder`.
+
+use ipwned_localdb::PwnedFilter;
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// a password as str, encoded as UTF-8 before hashing, or as bytes hashed as is
+#[derive(FromPyObject)]
+enum Password {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl AsRef<[u8]> for Password {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Password::Text(x) => x.as_bytes(),
+            Password::Bytes(x) => x,
+        }
+    }
+}
+
+fn to_sha1(hash: &[u8]) -> PyResult<&[u8; 20]> {
+    hash.try_into()
+        .map_err(|_| PyValueError::new_err(format!("expected 20 bytes, got {}", hash.len())))
+}
+
+/// copies the items of an iterable, so the lookups of the *_many methods can run without holding the GIL
+fn extract_all<'py, T: FromPyObjectOwned<'py>>(items: &Bound<'py, PyAny>) -> PyResult<Vec<T>> {
+    items
+        .try_iter()?
+        .map(|x| x?.extract().map_err(Into::into))
+        .collect()
+}
+
+/// A filter file written by ipwned-builder. Lookups may return false positives at the filter's error rate,
+/// but never false negatives.
+#[pyclass(name = "Filter", module = "ipwned", frozen)]
+struct Filter {
+    filter: PwnedFilter,
+}
+
+#[pymethods]
+impl Filter {
+    #[new]
+    fn new(py: Python<'_>, path: std::path::PathBuf) -> PyResult<Filter> {
+        let filter = py
+            .detach(|| PwnedFilter::open(&path))
+            .map_err(|e| PyOSError::new_err(format!("unable to open {}: {}", path.display(), e)))?;
+        Ok(Filter { filter })
+    }
+
+    /// whether the raw 20 byte SHA1 hash is in the filter
+    fn contains(&self, sha1: &[u8]) -> PyResult<bool> {
+        Ok(self.filter.contains(to_sha1(sha1)?))
+    }
+
+    /// whether the SHA1 hash given as 40 hex characters is in the filter
+    fn contains_hex(&self, hex: &str) -> PyResult<bool> {
+        self.filter
+            .contains_hex(hex)
+            .ok_or_else(|| PyValueError::new_err(format!("not a hex encoded SHA1 hash: {:?}", hex)))
+    }
+
+    /// whether the SHA1 hash of the password is in the filter, str is encoded as UTF-8
+    fn contains_password(&self, password: Password) -> bool {
+        self.filter.contains_password(password)
+    }
+
+    /// contains for each raw SHA1 hash of an iterable, returns a list of bools
+    fn contains_many(&self, py: Python<'_>, hashes: &Bound<'_, PyAny>) -> PyResult<Vec<bool>> {
+        let hashes: Vec<Vec<u8>> = extract_all(hashes)?;
+        py.detach(|| hashes.iter().map(|x| self.contains(x)).collect())
+    }
+
+    /// contains_hex for each hex encoded SHA1 hash of an iterable, returns a list of bools
+    fn contains_hex_many(&self, py: Python<'_>, hashes: &Bound<'_, PyAny>) -> PyResult<Vec<bool>> {
+        let hashes: Vec<String> = extract_all(hashes)?;
+        py.detach(|| hashes.iter().map(|x| self.contains_hex(x)).collect())
+    }
+
+    /// contains_password for each password of an iterable, returns a list of bools
+    fn contains_password_many(
+        &self,
+        py: Python<'_>,
+        passwords: &Bound<'_, PyAny>,
+    ) -> PyResult<Vec<bool>> {
+        let passwords: Vec<Password> = extract_all(passwords)?;
+        Ok(py.detach(|| {
+            passwords
+                .into_iter()
+                .map(|x| self.contains_password(x))
+                .collect()
+        }))
+    }
+
+    /// size and error rate of the filter, same as /info of ipwned-server
+    fn info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let filter = self.filter.info();
+        let info = PyDict::new(py);
+        info.set_item("entries", filter.entries)?;
+        info.set_item("capacity", filter.capacity)?;
+        info.set_item("max_error_rate", filter.max_error_rate)?;
+        info.set_item("current_error_rate", filter.current_error_rate)?;
+        info.set_item("memory_usage", filter.memory_usage)?;
+        Ok(info)
+    }
+
+    /// `hash in filter` for a raw SHA1 hash or a hex encoded one
+    fn __contains__(&self, hash: &Bound<'_, PyAny>) -> PyResult<bool> {
+        match hash.extract::<String>() {
+            Ok(hex) => self.contains_hex(&hex),
+            Err(_) => self.contains(&hash.extract::<Vec<u8>>()?),
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.filter.info().entries as usize
+    }
+}
+
+#[pymodule(name = "ipwned")]
+fn ipwned(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Filter>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::exceptions::PyTypeError;
+    use pyo3::types::{PyBytes, PyList, PyTuple};
+    use sha1::{Digest, Sha1};
+
+    /// filter with the SHA1 hash of "password", copied with a higher error rate like `export-client` does
+    fn filter() -> Filter {
+        let mut filter = qfilter::Filter::new(64, 1e-6).unwrap();
+        filter.insert(&Sha1::digest("password")[..]).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&filter, &mut cbor).unwrap();
+        let filter = PwnedFilter::from_reader(&cbor[..])
+            .unwrap()
+            .with_error_rate(1e-4)
+            .unwrap();
+        Filter { filter }
+    }
+
+    #[test]
+    fn open() {
+        Python::initialize();
+        Python::attach(|py| {
+            let path = std::env::temp_dir()
+                .join(format!("ipwned_python_open_test_{}", std::process::id()));
+            ciborium::into_writer(&filter().filter, std::fs::File::create(&path).unwrap()).unwrap();
+            let filter = Filter::new(py, path.clone()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(filter.contains_password(Password::Text("password".into())));
+            assert_eq!(filter.__len__(), 1);
+            let error = Filter::new(py, path).err().unwrap();
+            assert!(error.is_instance_of::<PyOSError>(py));
+        });
+    }
+
+    #[test]
+    fn contains_many() {
+        Python::initialize();
+        Python::attach(|py| {
+            let filter = filter();
+            let pwned = Sha1::digest("password");
+            let hashes = PyList::new(
+                py,
+                [
+                    PyBytes::new(py, &pwned),
+                    PyBytes::new(py, &[0; 20]),
+                    PyBytes::new(py, &pwned),
+                ],
+            )
+            .unwrap();
+            let found = filter.contains_many(py, hashes.as_any()).unwrap();
+            assert_eq!(found, [true, false, true]);
+
+            let hashes = PyTuple::new(py, [PyBytes::new(py, &pwned[..19])]).unwrap();
+            let error = filter.contains_many(py, hashes.as_any()).unwrap_err();
+            assert!(error.is_instance_of::<PyValueError>(py));
+            assert_eq!(error.value(py).to_string(), "expected 20 bytes, got 19");
+            let empty = PyList::empty(py);
+            assert!(filter.contains_many(py, empty.as_any()).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn contains_hex_many() {
+        Python::initialize();
+        Python::attach(|py| {
+            let filter = filter();
+            let pwned = format!("{:X}", Sha1::digest("password"));
+            let not_pwned = "0".repeat(40);
+            let hashes = PyList::new(py, [pwned.as_str(), &not_pwned]).unwrap();
+            let found = filter.contains_hex_many(py, hashes.as_any()).unwrap();
+            assert_eq!(found, [true, false]);
+
+            let hashes = PyList::new(py, [pwned.as_str(), "5baa61e4"]).unwrap();
+            let error = filter.contains_hex_many(py, hashes.as_any()).unwrap_err();
+            assert!(error.is_instance_of::<PyValueError>(py));
+            // items which are not str fail while they are copied, before any lookup
+            let hashes = PyList::new(py, [1, 2]).unwrap();
+            let error = filter.contains_hex_many(py, hashes.as_any()).unwrap_err();
+            assert!(error.is_instance_of::<PyTypeError>(py));
+            let error = filter.contains_hex_many(py, 1u8.into_pyobject(py).unwrap().as_any());
+            assert!(error.unwrap_err().is_instance_of::<PyTypeError>(py));
+        });
+    }
+
+    #[test]
+    fn contains_password_many() {
+        Python::initialize();
+        Python::attach(|py| {
+            let filter = filter();
+            let passwords = PyList::new(
+                py,
+                [
+                    "password".into_pyobject(py).unwrap().into_any(),
+                    PyBytes::new(py, b"password").into_any(),
+                    "not pwned".into_pyobject(py).unwrap().into_any(),
+                ],
+            )
+            .unwrap();
+            let found = filter
+                .contains_password_many(py, passwords.as_any())
+                .unwrap();
+            assert_eq!(found, [true, true, false]);
+            let error = filter.contains_password_many(py, 1u8.into_pyobject(py).unwrap().as_any());
+            assert!(error.unwrap_err().is_instance_of::<PyTypeError>(py));
+        });
+    }
+
+    #[test]
+    fn info() {
+        Python::initialize();
+        Python::attach(|py| {
+            let filter = filter();
+            let info = filter.info(py).unwrap();
+            let expected = filter.filter.info();
+            let get = |key: &str| info.get_item(key).unwrap().unwrap();
+            assert_eq!(get("entries").extract::<u64>().unwrap(), 1);
+            assert_eq!(get("capacity").extract::<u64>().unwrap(), expected.capacity);
+            assert_eq!(
+                get("max_error_rate").extract::<f64>().unwrap(),
+                expected.max_error_rate
+            );
+            assert_eq!(info.len(), 5);
+        });
+    }
+}
@@ -0,0 +1,154 @@
+use bytes::Bytes;
+use chrono::{Local, NaiveDate};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct AllowlistEntry {
+    pub hash: Bytes,
+    pub expires: NaiveDate,
+    pub reason: String,
+}
+
+/// Documented exceptions for hashes that should never be reported as pwned.
+///
+/// The file contains one entry per line: `<sha1 hex> <expiry YYYY-MM-DD> <reason>`.
+/// Empty lines and lines starting with `#` are ignored. An entry is active up to and including its expiry date.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    entries: HashMap<Bytes, AllowlistEntry>,
+}
+
+impl Allowlist {
+    pub fn open(path: &Path) -> io::Result<Allowlist> {
+        let content = std::fs::read_to_string(path)?;
+        let mut entries = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_entry(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed allowlist entry on line {}", i + 1),
+                )
+            })?;
+            entries.insert(entry.hash.clone(), entry);
+        }
+        Ok(Allowlist { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// whether the hash has an active entry at the given date
+    pub fn contains_at(&self, hash: &[u8], date: NaiveDate) -> bool {
+        self.entries.get(hash).is_some_and(|x| x.expires >= date)
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.contains_at(hash, Local::now().date_naive())
+    }
+
+    /// all entries which have not expired at the given date
+    pub fn active_at(&self, date: NaiveDate) -> impl Iterator<Item = &AllowlistEntry> {
+        self.entries.values().filter(move |x| x.expires >= date)
+    }
+}
+
+fn parse_entry(line: &str) -> Option<AllowlistEntry> {
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let hex = parts.next()?;
+    let expires = NaiveDate::parse_from_str(parts.next()?, "%Y-%m-%d").ok()?;
+    let reason = parts.next()?.trim().to_string();
+    if hex.len() != 40 || reason.is_empty() {
+        return None;
+    }
+    let mut hash = vec![0; 20];
+    faster_hex::hex_decode(hex.as_bytes(), &mut hash).ok()?;
+    Some(AllowlistEntry {
+        hash: Bytes::from(hash),
+        expires,
+        reason,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3";
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn entry() {
+        let entry = parse_entry(&format!("{} 2030-01-31 test  account\tof QA", SHA1)).unwrap();
+        let mut hash = [0u8; 20];
+        faster_hex::hex_decode(SHA1.as_bytes(), &mut hash).unwrap();
+        assert_eq!(&entry.hash[..], &hash);
+        assert_eq!(entry.expires, date("2030-01-31"));
+        assert_eq!(entry.reason, "test  account\tof QA");
+        let upper = parse_entry(&format!("{} 2030-01-31 test", SHA1.to_uppercase())).unwrap();
+        assert_eq!(upper.hash, entry.hash);
+    }
+
+    #[test]
+    fn malformed_entry() {
+        for line in [
+            format!("{} 2030-01-31", SHA1),
+            format!("{} 2030-01-31  ", SHA1),
+            format!("{} 2030-02-30 test", SHA1),
+            format!("{} 31.01.2030 test", SHA1),
+            format!("{} test", SHA1),
+            format!("{}0 2030-01-31 test", SHA1),
+            format!("{} 2030-01-31 test", &SHA1[..39]),
+            format!("{}x 2030-01-31 test", &SHA1[..39]),
+            // NTLM hashes are 32 hex characters, the allowlist only holds SHA1 hashes
+            String::from("8846f7eaee8fb117ad06bdd830b7586c 2030-01-31 test"),
+        ] {
+            assert!(parse_entry(&line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn open() {
+        let path =
+            std::env::temp_dir().join(format!("ipwned_allowlist_test_{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "# comment\n\n  {sha1} 2030-01-31 test account  \n\
+                 0000000000000000000000000000000000000000 2020-01-01 expired\n",
+                sha1 = SHA1
+            ),
+        )
+        .unwrap();
+        let allowlist = Allowlist::open(&path).unwrap();
+        assert_eq!(allowlist.len(), 2);
+        let mut hash = [0u8; 20];
+        faster_hex::hex_decode(SHA1.as_bytes(), &mut hash).unwrap();
+        assert!(allowlist.contains_at(&hash, date("2030-01-31")));
+        assert!(!allowlist.contains_at(&hash, date("2030-02-01")));
+        assert!(!allowlist.contains_at(&[0; 20], date("2020-01-02")));
+        assert!(allowlist.contains_at(&[0; 20], date("2020-01-01")));
+        let active: Vec<_> = allowlist.active_at(date("2025-01-01")).collect();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].reason, "test account");
+
+        std::fs::write(&path, format!("{} 2030-01-31 test\n\nnot a hash\n", SHA1)).unwrap();
+        let error = Allowlist::open(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "malformed allowlist entry on line 3");
+    }
+}
@@ -0,0 +1,479 @@
+use argh::FromArgs;
+use ipwned_localdb::allowlist::Allowlist;
+use ipwned_localdb::pwned_filter::{HashType, PwnedFilter};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const EXIT_NOT_PWNED: u8 = 0;
+const EXIT_PWNED: u8 = 1;
+const EXIT_ERROR: u8 = 2;
+const EXIT_BAD_ARGS: u8 = 255;
+
+/// invalid records reported individually, further ones are only counted
+const MAX_REPORTED_INVALID: u64 = 10;
+
+#[derive(FromArgs)]
+/// audit exported password hashes of user accounts against a local haveibeenpwned.com password lookup table
+struct CliArgs {
+    /// file with one account:hash record per line or a CSV file with --csv. default: stdin
+    #[argh(positional)]
+    input: Option<PathBuf>,
+
+    /// file name of the SHA1 lookup filter file. default: ./ipwned_qfilter.cbor
+    #[argh(option, short = 'f', default = "String::from(\"ipwned_qfilter.cbor\")")]
+    filter_path: String,
+
+    /// file name of an NTLM lookup filter file, built with ipwned-builder --hash-type ntlm. NTLM hashes are not checked without it. default: none
+    #[argh(option)]
+    ntlm_filter_path: Option<String>,
+
+    /// allowlist of SHA1 hashes that are never reported as pwned, see Readme for the file format. NTLM hashes are not covered by it. default: none
+    #[argh(option, short = 'a')]
+    allowlist: Option<PathBuf>,
+
+    /// read the input as CSV with a header row
+    #[argh(switch)]
+    csv: bool,
+
+    /// field delimiter of the CSV input. default: ,
+    #[argh(option, default = "','")]
+    delimiter: char,
+
+    /// CSV column of the account, given by header name or 1-based number. default: 1
+    #[argh(option, default = "String::from(\"1\")")]
+    account_column: String,
+
+    /// CSV column of the hash, given by header name or 1-based number. default: 2
+    #[argh(option, default = "String::from(\"2\")")]
+    hash_column: String,
+
+    /// write the report to this file instead of stdout
+    #[argh(option, short = 'o')]
+    output: Option<PathBuf>,
+
+    /// write the report as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    records: u64,
+    checked: u64,
+    pwned: u64,
+    /// number of different passwords among the pwned accounts
+    pwned_passwords: u64,
+    /// records without an account or a hex encoded SHA1 or NTLM hash
+    invalid: u64,
+    /// NTLM hashes which were not checked, since no NTLM filter was given
+    unchecked: u64,
+}
+
+#[derive(Serialize)]
+struct AffectedAccount {
+    account: String,
+    hash_type: &'static str,
+    /// number of accounts in the input with the same password hash, including this one
+    same_password: u32,
+}
+
+#[derive(Serialize)]
+struct Report {
+    summary: Summary,
+    affected: Vec<AffectedAccount>,
+}
+
+struct Audit {
+    filter: PwnedFilter,
+    ntlm_filter: Option<PwnedFilter>,
+    allowlist: Allowlist,
+    summary: Summary,
+    /// pwned accounts in input order, with the index of their hash in `same_password`
+    affected: Vec<(String, HashType, usize)>,
+    /// index of every pwned hash, the hashes themselves are not part of the report
+    pwned_hashes: HashMap<Vec<u8>, usize>,
+    /// number of accounts using each pwned hash
+    same_password: Vec<u32>,
+}
+
+impl Audit {
+    /// checks the account and hash of a record, `None` if the record does not contain both
+    fn check(&mut self, record: u64, fields: Option<(&str, &str)>) {
+        self.summary.records += 1;
+        let Some((account, hex)) = fields else {
+            return self.invalid(record);
+        };
+        let hex = hex.trim();
+        let mut hash = [0u8; 20];
+        let hash_type = match hex.len() {
+            40 => HashType::Sha1,
+            32 => HashType::Ntlm,
+            _ => return self.invalid(record),
+        };
+        let hash = &mut hash[..hash_type.size()];
+        if account.is_empty() || faster_hex::hex_decode(hex.as_bytes(), hash).is_err() {
+            return self.invalid(record);
+        }
+        let hash = &*hash;
+        let pwned = match (hash_type, &self.ntlm_filter) {
+            (HashType::Sha1, _) => {
+                !self.allowlist.contains(hash) && self.filter.contains(hash.try_into().unwrap())
+            }
+            (HashType::Ntlm, Some(filter)) => filter.contains_ntlm(hash.try_into().unwrap()),
+            (HashType::Ntlm, None) => {
+                self.summary.unchecked += 1;
+                return;
+            }
+        };
+        self.summary.checked += 1;
+        if !pwned {
+            return;
+        }
+        self.summary.pwned += 1;
+        let next = self.pwned_hashes.len();
+        let index = *self.pwned_hashes.entry(hash.to_vec()).or_insert(next);
+        if index == next {
+            self.same_password.push(0);
+        }
+        self.same_password[index] += 1;
+        self.affected.push((account.to_string(), hash_type, index));
+    }
+
+    fn invalid(&mut self, record: u64) {
+        self.summary.invalid += 1;
+        if self.summary.invalid <= MAX_REPORTED_INVALID {
+            eprintln!(
+                "record {}: expected an account and a SHA1 or NTLM hash",
+                record
+            );
+        }
+    }
+
+    fn into_report(mut self) -> Report {
+        self.summary.pwned_passwords = self.pwned_hashes.len() as u64;
+        let affected = self
+            .affected
+            .into_iter()
+            .map(|(account, hash_type, index)| AffectedAccount {
+                account,
+                hash_type: hash_type.as_str(),
+                same_password: self.same_password[index],
+            })
+            .collect();
+        Report {
+            summary: self.summary,
+            affected,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: CliArgs = argh::from_env();
+    if !args.delimiter.is_ascii() {
+        eprintln!("--delimiter must be a single ASCII character");
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
+    let filter = match open_filter(&args.filter_path) {
+        Some(x) => x,
+        None => return ExitCode::from(EXIT_ERROR),
+    };
+    let ntlm_filter = match &args.ntlm_filter_path {
+        Some(path) => match open_filter(path) {
+            Some(x) => Some(x),
+            None => return ExitCode::from(EXIT_ERROR),
+        },
+        None => None,
+    };
+    let allowlist = match &args.allowlist {
+        Some(path) => match Allowlist::open(path) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("unable to read allowlist {}: {}", path.display(), e);
+                return ExitCode::from(EXIT_ERROR);
+            }
+        },
+        None => Allowlist::default(),
+    };
+    let mut audit = Audit {
+        filter,
+        ntlm_filter,
+        allowlist,
+        summary: Summary::default(),
+        affected: Vec::new(),
+        pwned_hashes: HashMap::new(),
+        same_password: Vec::new(),
+    };
+
+    let input_name = args
+        .input
+        .as_ref()
+        .map_or(String::from("stdin"), |x| x.display().to_string());
+    let input: Box<dyn Read> = match &args.input {
+        Some(path) => match File::open(path) {
+            Ok(x) => Box::new(x),
+            Err(e) => {
+                eprintln!("unable to open {}: {}", input_name, e);
+                return ExitCode::from(EXIT_ERROR);
+            }
+        },
+        None => Box::new(io::stdin().lock()),
+    };
+    let result = match args.csv {
+        true => audit_csv(&mut audit, input, &args),
+        false => audit_lines(&mut audit, input),
+    };
+    if let Err(e) = result {
+        eprintln!("unable to read {}: {}", input_name, e);
+        return ExitCode::from(EXIT_ERROR);
+    }
+
+    let report = audit.into_report();
+    if report.summary.unchecked > 0 {
+        eprintln!(
+            "{} NTLM hashes were not checked, use --ntlm-filter-path to check them",
+            report.summary.unchecked
+        );
+    }
+    if let Err(e) = write_report(&report, &args) {
+        eprintln!("unable to write report: {}", e);
+        return ExitCode::from(EXIT_ERROR);
+    }
+    ExitCode::from(exit_code(&report.summary))
+}
+
+/// affected accounts take precedence, unchecked NTLM hashes are an error so an incomplete audit doesn't look clean
+fn exit_code(summary: &Summary) -> u8 {
+    match (summary.pwned, summary.unchecked) {
+        (0, 0) => EXIT_NOT_PWNED,
+        (0, _) => EXIT_ERROR,
+        _ => EXIT_PWNED,
+    }
+}
+
+fn open_filter(path: &str) -> Option<PwnedFilter> {
+    match PwnedFilter::open(path) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            eprintln!("unable to read filter file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// `account:hash` records, the account may contain colons itself
+fn audit_lines(audit: &mut Audit, input: Box<dyn Read>) -> io::Result<()> {
+    for (i, line) in BufReader::new(input).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        audit.check(i as u64 + 1, line.rsplit_once(':'));
+    }
+    Ok(())
+}
+
+fn audit_csv(audit: &mut Audit, input: Box<dyn Read>, args: &CliArgs) -> io::Result<()> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(args.delimiter as u8)
+        .flexible(true)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    let account_column = column_index(&headers, &args.account_column)?;
+    let hash_column = column_index(&headers, &args.hash_column)?;
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        // the header is record 1
+        let record_no = i as u64 + 2;
+        let fields = record.get(account_column).zip(record.get(hash_column));
+        audit.check(record_no, fields);
+    }
+    Ok(())
+}
+
+/// a column given by header name or 1-based number
+fn column_index(headers: &csv::StringRecord, column: &str) -> io::Result<usize> {
+    if let Some(number) = column.parse::<usize>().ok().filter(|x| *x > 0) {
+        return Ok(number - 1);
+    }
+    headers.iter().position(|x| x == column).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no column {} in the CSV header", column),
+        )
+    })
+}
+
+fn write_report(report: &Report, args: &CliArgs) -> io::Result<()> {
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    });
+    if args.json {
+        serde_json::to_writer_pretty(&mut writer, report)?;
+        writeln!(writer)?;
+    } else {
+        write_text_report(&mut writer, report)?;
+    }
+    writer.flush()
+}
+
+fn write_text_report<W: Write>(writer: &mut W, report: &Report) -> io::Result<()> {
+    let summary = &report.summary;
+    let percent = match summary.checked {
+        0 => 0.,
+        checked => summary.pwned as f64 * 100. / checked as f64,
+    };
+    writeln!(writer, "records:          {}", summary.records)?;
+    writeln!(writer, "checked:          {}", summary.checked)?;
+    writeln!(
+        writer,
+        "pwned:            {} ({:.2}%)",
+        summary.pwned, percent
+    )?;
+    writeln!(writer, "pwned passwords:  {}", summary.pwned_passwords)?;
+    writeln!(writer, "invalid:          {}", summary.invalid)?;
+    writeln!(writer, "unchecked (NTLM): {}", summary.unchecked)?;
+    if report.affected.is_empty() {
+        return Ok(());
+    }
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "account\thash type\taccounts with the same password"
+    )?;
+    for affected in &report.affected {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            affected.account, affected.hash_type, affected.same_password
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+
+    /// audit of a SHA1 filter with "password" and "123456", without an NTLM filter
+    fn audit() -> Audit {
+        let mut filter = qfilter::Filter::new(64, 1e-6).unwrap();
+        for password in ["password", "123456"] {
+            filter.insert(&Sha1::digest(password)[..]).unwrap();
+        }
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&filter, &mut cbor).unwrap();
+        Audit {
+            filter: PwnedFilter::from_reader(&cbor[..]).unwrap(),
+            ntlm_filter: None,
+            allowlist: Allowlist::default(),
+            summary: Summary::default(),
+            affected: Vec::new(),
+            pwned_hashes: HashMap::new(),
+            same_password: Vec::new(),
+        }
+    }
+
+    fn sha1(password: &str) -> String {
+        format!("{:x}", Sha1::digest(password))
+    }
+
+    fn affected(report: &Report) -> Vec<(&str, &str, u32)> {
+        report
+            .affected
+            .iter()
+            .map(|x| (x.account.as_str(), x.hash_type, x.same_password))
+            .collect()
+    }
+
+    #[test]
+    fn column() {
+        let headers = csv::StringRecord::from(vec!["user", "pwdhash", "2"]);
+        assert_eq!(column_index(&headers, "user").unwrap(), 0);
+        assert_eq!(column_index(&headers, "pwdhash").unwrap(), 1);
+        // numbers are columns, even if a header has the same name
+        assert_eq!(column_index(&headers, "1").unwrap(), 0);
+        assert_eq!(column_index(&headers, "2").unwrap(), 1);
+        assert_eq!(column_index(&headers, "10").unwrap(), 9);
+        let error = column_index(&headers, "0").unwrap_err();
+        assert_eq!(error.to_string(), "no column 0 in the CSV header");
+        let error = column_index(&headers, "email").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn lines() {
+        let mut audit = audit();
+        let input = format!(
+            "alice:{}\nDOMAIN\\bob:x:{}\r\n\n  \ncarol:{}\nno hash\n:{}\ndave:{}\n",
+            sha1("password"),
+            sha1("password").to_uppercase(),
+            sha1("not pwned"),
+            sha1("password"),
+            sha1("123456"),
+        );
+        audit_lines(&mut audit, Box::new(io::Cursor::new(input))).unwrap();
+        let report = audit.into_report();
+        assert_eq!(
+            affected(&report),
+            [
+                ("alice", "sha1", 2),
+                ("DOMAIN\\bob:x", "sha1", 2),
+                ("dave", "sha1", 1)
+            ]
+        );
+        let summary = &report.summary;
+        assert_eq!(
+            (summary.records, summary.checked, summary.invalid),
+            (6, 4, 2)
+        );
+        assert_eq!((summary.pwned, summary.pwned_passwords), (3, 2));
+        assert_eq!(exit_code(summary), EXIT_PWNED);
+    }
+
+    #[test]
+    fn same_password() {
+        let mut audit = audit();
+        let (password, other) = (sha1("password"), sha1("123456"));
+        for (i, hash) in [&password, &other, &password, &password, &other]
+            .iter()
+            .enumerate()
+        {
+            audit.check(i as u64 + 1, Some((&format!("user{}", i), hash)));
+        }
+        let report = audit.into_report();
+        assert_eq!(
+            affected(&report),
+            [
+                ("user0", "sha1", 3),
+                ("user1", "sha1", 2),
+                ("user2", "sha1", 3),
+                ("user3", "sha1", 3),
+                ("user4", "sha1", 2)
+            ]
+        );
+        assert_eq!(report.summary.pwned_passwords, 2);
+    }
+
+    #[test]
+    fn unchecked_ntlm() {
+        let mut audit = audit();
+        let ntlm = faster_hex::hex_string(&ipwned_localdb::pwned_filter::ntlm("password"));
+        audit.check(1, Some(("alice", &ntlm)));
+        audit.check(2, Some(("bob", &sha1("not pwned"))));
+        assert_eq!((audit.summary.checked, audit.summary.unchecked), (1, 1));
+        assert_eq!(exit_code(&audit.summary), EXIT_ERROR);
+        audit.check(3, Some(("carol", &sha1("password"))));
+        assert_eq!(exit_code(&audit.summary), EXIT_PWNED);
+
+        let mut audit = self::audit();
+        audit.check(1, Some(("bob", &sha1("not pwned"))));
+        audit.check(2, None);
+        assert_eq!(exit_code(&audit.summary), EXIT_NOT_PWNED);
+    }
+}
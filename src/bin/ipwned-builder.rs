@@ -1,35 +1,53 @@
-#[path = "../downloader.rs"]
-mod downloader;
-#[path = "../filter_builder.rs"]
-mod filter_builder;
-#[path = "../misc.rs"]
-mod misc;
-#[path = "../parse.rs"]
-mod parse;
-#[path = "../statedb.rs"]
-mod statedb;
-
-use crate::downloader::download_retry;
-use crate::filter_builder::{FilterBuilder, FilterResult, HashList};
-use crate::misc::{DownloadError, DownloadStatus, MAX_COUNT};
-use crate::statedb::{State, StateDatabase};
 use argh::FromArgs;
+use bytes::Bytes;
 use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeDelta};
-use futures;
+use futures::stream::FuturesUnordered;
 use futures::{StreamExt, pin_mut, stream};
-use indicatif;
 use indicatif_log_bridge::LogWrapper;
+use ipwned_localdb::allowlist::Allowlist;
+use ipwned_localdb::downloader::{download_retry, normalize_etag};
+use ipwned_localdb::filter_builder::{
+    FilterBuilder, FilterResult, FilterStats, HashList, ListFormat, ListMeta, write_filter,
+};
+use ipwned_localdb::import::DumpReader;
+use ipwned_localdb::logging::{JsonLogger, LogFormat, write_json_line};
+use ipwned_localdb::misc::{DownloadError, DownloadStatus, MAX_COUNT};
+use ipwned_localdb::pwned_filter::{HashType, PwnedFilter};
+use ipwned_localdb::statedb::{Failure, RangeUpdate, State, StateDatabase};
 use log::{LevelFilter, debug, error, info, warn};
 use pretty_duration::pretty_duration;
 use reqwest::Client;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env::current_dir;
 use std::fmt::Write;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::str::FromStr;
-use std::time::Duration;
-use tokio;
-use tokio::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::MissedTickBehavior;
+
+/// minimum time between checks for outdated ranges in daemon mode
+const RESCAN_INTERVAL: Duration = Duration::from_secs(600);
+/// typical size of a range list, used by --dry-run for ranges without a stored size
+const TYPICAL_RANGE_SIZE: u64 = 32 * 1024;
+
+// exit codes, see Readme
+const EXIT_OK: u8 = 0;
+/// state database, allowlist or input file could not be opened
+const EXIT_OPEN_FAILED: u8 = 1;
+const EXIT_INTERNAL: u8 = 2;
+/// writing the state database or filter file failed, or reading the input failed
+const EXIT_IO_ERROR: u8 = 3;
+/// some ranges or extra sources could not be downloaded or read
+const EXIT_PARTIAL: u8 = 4;
+/// the filter is full, a new filter with a higher --max-count is required
+const EXIT_CAPACITY: u8 = 5;
+const EXIT_BAD_ARGS: u8 = 255;
 
 #[derive(FromArgs)]
 /// Create or update a local lookup table for haveibeenpwned.com compromised passwords
@@ -38,13 +56,17 @@ struct CliArgs {
     #[argh(option, short = 'd', default = "current_dir().unwrap()")]
     base_path: PathBuf,
 
-    /// file name of the state database file. default: ipwned_state.sqlite
-    #[argh(option, short = 's', default = "String::from(\"ipwned_state.sqlite\")")]
-    state_db_name: String,
+    /// file name of the state database file. default: ipwned_state.sqlite, ipwned_ntlm_state.sqlite for --hash-type ntlm
+    #[argh(option, short = 's')]
+    state_db_name: Option<String>,
+
+    /// file name of the lookup filter file. default: ipwned_qfilter.cbor, ipwned_ntlm_qfilter.cbor for --hash-type ntlm
+    #[argh(option, short = 'f')]
+    filter_name: Option<String>,
 
-    /// file name of the lookup filter file. default: ipwned_qfilter.cbor
-    #[argh(option, short = 'f', default = "String::from(\"ipwned_qfilter.cbor\")")]
-    filter_name: String,
+    /// hash type of the lists to download. allowed options: sha1 ntlm. default: sha1
+    #[argh(option, default = "HashType::Sha1")]
+    hash_type: HashType,
 
     /// maximum age of a downloaded file before attempting an update. accepts a human-friendly string. default: 1 month
     #[argh(option, short = 'a', default = "String::from(\"1 month\")")]
@@ -85,49 +107,374 @@ struct CliArgs {
     /// log level. allowed options: off error warn info debug trace. default: warn
     #[argh(option, short = 'l', default = "String::from(\"warn\")")]
     log: String,
+
+    /// log format. allowed options: text json. default: text
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+
+    /// interval for progress lines when stderr is not a terminal or --log-format is json. accepts a human-friendly string. default: 1 minute
+    #[argh(option, default = "String::from(\"1 minute\")")]
+    progress_interval: String,
+
+    /// additional list of SHA1 hashes in hex (one per line, optionally followed by :COUNT) to add to the filter. can be repeated
+    #[argh(option)]
+    extra_sha1: Vec<PathBuf>,
+
+    /// additional list of plaintext passwords (one per line) to hash and add to the filter. can be repeated
+    #[argh(option)]
+    extra_plaintext: Vec<PathBuf>,
+
+    /// allowlist of hashes to subtract from the filter, see Readme for the file format. not supported with --hash-type ntlm. default: none
+    #[argh(option)]
+    allowlist: Option<PathBuf>,
+
+    /// write a JSON summary of the run to this file, - for stdout. default: none
+    #[argh(option)]
+    summary_json: Option<PathBuf>,
+
+    /// only report how many ranges would be downloaded and estimate the download size, without any requests
+    #[argh(switch)]
+    dry_run: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Import(ImportArgs),
+    Daemon(DaemonArgs),
+    RetryFailed(RetryFailedArgs),
+    Status(StatusArgs),
+    Show(ShowArgs),
+    ExportClient(ExportClientArgs),
+}
+
+#[derive(FromArgs)]
+/// import hashes from a downloaded pwnedpasswords dump instead of the range API
+#[argh(subcommand, name = "import")]
+struct ImportArgs {
+    /// text file with one HASH:COUNT entry per line, e.g. pwned-passwords-sha1-ordered-by-hash.txt
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+#[derive(FromArgs)]
+/// keep running and refresh ranges once they are older than --max-age, spreading the requests over that interval
+#[argh(subcommand, name = "daemon")]
+struct DaemonArgs {
+    /// interval for writing the filter to disk if it changed. accepts a human-friendly string. default: 1 hour
+    #[argh(option, default = "String::from(\"1 hour\")")]
+    checkpoint_interval: String,
+
+    /// pid file of a running ipwned-server, which is sent SIGHUP to reload the filter after it was written. default: none
+    #[argh(option)]
+    notify_pid_file: Option<PathBuf>,
+}
+
+impl DaemonArgs {
+    /// the pause between two range updates and the checkpoint interval, tokio timers panic on zero intervals
+    fn intervals(&self, args: &CliArgs) -> Result<(Duration, Duration), String> {
+        let max_age = parse_duration::parse(&args.max_age)
+            .map_err(|e| format!("invalid --max-age {}: {}", args.max_age, e))?;
+        let checkpoint = parse_duration::parse(&self.checkpoint_interval).map_err(|e| {
+            format!(
+                "invalid --checkpoint-interval {}: {}",
+                self.checkpoint_interval, e
+            )
+        })?;
+        // spread updates of all ranges evenly over max_age
+        let pace = max_age / (args.end - args.start + 1);
+        if pace.is_zero() {
+            return Err(String::from(
+                "--max-age is too short to spread the range updates over",
+            ));
+        }
+        if checkpoint.is_zero() {
+            return Err(String::from("--checkpoint-interval must be longer than 0"));
+        }
+        Ok((pace, checkpoint))
+    }
+}
+
+#[derive(FromArgs)]
+/// only download ranges which failed in previous runs, waiting longer each time a range keeps failing
+#[argh(subcommand, name = "retry-failed")]
+struct RetryFailedArgs {
+    /// time to wait before retrying a range after its first failure, doubled for every further failure. accepts a human-friendly string. default: 1 hour
+    #[argh(option, default = "String::from(\"1 hour\")")]
+    backoff: String,
+
+    /// maximum time to wait before retrying a range. accepts a human-friendly string. default: 1 week
+    #[argh(option, default = "String::from(\"1 week\")")]
+    max_backoff: String,
+}
+
+impl RetryFailedArgs {
+    /// the backoff after the first failure and the maximum backoff
+    fn backoffs(&self) -> Result<(Duration, Duration), String> {
+        let backoff = parse_duration::parse(&self.backoff)
+            .map_err(|e| format!("invalid --backoff {}: {}", self.backoff, e))?;
+        let max_backoff = parse_duration::parse(&self.max_backoff)
+            .map_err(|e| format!("invalid --max-backoff {}: {}", self.max_backoff, e))?;
+        Ok((backoff, max_backoff))
+    }
+}
+
+#[derive(FromArgs)]
+/// report the state of the ranges between --start and --end without changing anything
+#[argh(subcommand, name = "status")]
+struct StatusArgs {}
+
+#[derive(FromArgs)]
+/// show the stored state of a single range without changing anything
+#[argh(subcommand, name = "show")]
+struct ShowArgs {
+    /// range prefix, 5 hex characters. a longer SHA1 hash selects its range
+    #[argh(positional)]
+    prefix: String,
+}
+
+#[derive(FromArgs)]
+/// write a smaller copy of the filter with a higher false positive rate, e.g. for browsers
+#[argh(subcommand, name = "export-client")]
+struct ExportClientArgs {
+    /// maximum error rate (false positives) of the copy, must not be lower than the error rate of the filter. default: 0.001
+    #[argh(option, default = "0.001")]
+    error_rate: f64,
+
+    /// file name of the copy, relative to --base-path. default: ipwned_qfilter_client.cbor
+    #[argh(option, default = "String::from(\"ipwned_qfilter_client.cbor\")")]
+    output: String,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Import(_) => "import",
+            Command::Daemon(_) => "daemon",
+            Command::RetryFailed(_) => "retry-failed",
+            Command::Status(_) => "status",
+            Command::Show(_) => "show",
+            Command::ExportClient(_) => "export-client",
+        }
+    }
 }
 
 impl CliArgs {
     pub fn state_db_path(&self) -> PathBuf {
         let mut path = self.base_path.to_owned();
-        path.push(&self.state_db_name);
+        path.push(
+            self.state_db_name
+                .as_deref()
+                .unwrap_or(match self.hash_type {
+                    HashType::Sha1 => "ipwned_state.sqlite",
+                    HashType::Ntlm => "ipwned_ntlm_state.sqlite",
+                }),
+        );
         path
     }
 
     pub fn filter_path(&self) -> PathBuf {
         let mut path = self.base_path.to_owned();
-        path.push(&self.filter_name);
+        path.push(self.filter_name.as_deref().unwrap_or(match self.hash_type {
+            HashType::Sha1 => "ipwned_qfilter.cbor",
+            HashType::Ntlm => "ipwned_ntlm_qfilter.cbor",
+        }));
         path
     }
 
+    /// interval of the progress lines printed without a terminal
+    pub fn progress_interval(&self) -> Result<Duration, String> {
+        parse_duration::parse(&self.progress_interval).map_err(|e| {
+            format!(
+                "invalid --progress-interval {}: {}",
+                self.progress_interval, e
+            )
+        })
+    }
+
+    /// format of the downloaded or imported ranges
+    pub fn range_format(&self) -> ListFormat {
+        match self.hash_type {
+            HashType::Sha1 => ListFormat::Range,
+            HashType::Ntlm => ListFormat::NtlmRange,
+        }
+    }
+
     pub fn log_level(&self) -> LevelFilter {
         LevelFilter::from_str(&self.log).unwrap()
     }
+
+    pub fn extra_sources(&self) -> Vec<(&Path, ListFormat)> {
+        let sha1 = self
+            .extra_sha1
+            .iter()
+            .map(|x| (x.as_path(), ListFormat::Sha1Hex));
+        let plaintext = self.extra_plaintext.iter().map(|x| match self.hash_type {
+            HashType::Sha1 => (x.as_path(), ListFormat::Plaintext),
+            HashType::Ntlm => (x.as_path(), ListFormat::NtlmPlaintext),
+        });
+        sha1.chain(plaintext).collect()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 struct Status {
     pub total: u32,
     pub skipped: u32,
+    /// downloaded ranges with the same content as before, these were not parsed again
+    pub unchanged: u32,
     pub downloaded: u32,
     pub downloaded_bytes: u64,
     pub hashes: u32,
     pub hashes_new: u32,
+    #[serde(rename = "errors")]
     pub error: u32,
     pub processed: u32,
+    /// failed updates of the state database
+    pub db_errors: u32,
+    pub failed: Vec<FailedRange>,
+}
+
+#[derive(Debug, Serialize)]
+struct FailedRange {
+    pub id: u32,
+    pub prefix: String,
+    /// HTTP status of the last attempt, none for connection errors
+    pub status_code: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    mode: &'static str,
+    exit_code: u8,
+    started: String,
+    duration_secs: f64,
+    #[serde(flatten)]
+    status: &'a Status,
+    filter: Option<FilterStats>,
+}
+
+/// allowlisted hashes are subtracted from the filter when they are found in a hash list.
+/// The state database tracks which entries were checked, so ranges only need to be re-downloaded once for new entries
+/// and expired entries can be added back to the filter.
+struct AllowlistState {
+    /// hashes of all active entries
+    active: HashSet<Bytes>,
+    /// active entries grouped by range id
+    by_range: HashMap<u32, Vec<Bytes>>,
+    /// ranges with active entries that were not checked against the range's hash list yet
+    unchecked_ranges: HashSet<u32>,
+    /// previously subtracted hashes whose entry has expired or was removed
+    restore: Vec<Bytes>,
+    /// checked hashes whose entry has expired or was removed
+    stale: Vec<Bytes>,
+}
+
+impl AllowlistState {
+    pub async fn new(allowlist: &Allowlist, state_db: &StateDatabase) -> AllowlistState {
+        let active: HashSet<Bytes> = allowlist
+            .active_at(Local::now().date_naive())
+            .map(|x| x.hash.clone())
+            .collect();
+        let mut by_range: HashMap<u32, Vec<Bytes>> = HashMap::new();
+        for hash in &active {
+            by_range
+                .entry(range_id(hash))
+                .or_default()
+                .push(hash.clone());
+        }
+        let checked = state_db.fetch_allowlisted().await.unwrap_or_else(|e| {
+            error!("Failed to read allowlist state: {}", e);
+            Vec::new()
+        });
+        let mut unchecked: HashSet<Bytes> = active.clone();
+        let mut restore = Vec::new();
+        let mut stale = Vec::new();
+        for (hash, in_filter) in checked {
+            let hash = Bytes::from(hash);
+            if active.contains(&hash) {
+                unchecked.remove(&hash);
+                continue;
+            }
+            if in_filter {
+                restore.push(hash.clone());
+            }
+            stale.push(hash);
+        }
+        AllowlistState {
+            active,
+            by_range,
+            unchecked_ranges: unchecked.iter().map(|x| range_id(x)).collect(),
+            restore,
+            stale,
+        }
+    }
+
+    /// forgets the stale hashes once a filter builder ran and wrote the filter with the restored ones, otherwise they
+    /// are restored by a later run
+    pub async fn remove_stale(
+        &self,
+        state_db: &StateDatabase,
+        filter_stats: &Option<FilterStats>,
+        status: &mut Status,
+    ) {
+        if !filter_stats.as_ref().is_some_and(|x| x.restored) {
+            return;
+        }
+        for hash in &self.stale {
+            if !state_db.remove_allowlisted(hash.to_vec()).await {
+                error!("failed to update allowlist state in state db");
+                status.db_errors += 1;
+            }
+        }
+    }
+}
+
+fn range_id(hash: &[u8]) -> u32 {
+    ((hash[0] as u32) << 12) | ((hash[1] as u32) << 4) | ((hash[2] as u32) >> 4)
 }
 
 impl Status {
     pub fn new(total: u32) -> Status {
         Status {
-            total: total,
+            total,
             skipped: 0,
+            unchanged: 0,
             downloaded: 0,
             downloaded_bytes: 0,
             hashes: 0,
             hashes_new: 0,
             error: 0,
             processed: 0,
+            db_errors: 0,
+            failed: Vec::new(),
+        }
+    }
+
+    pub fn count_download(&mut self, result: &Result<usize, DownloadStatus>) {
+        self.processed += 1;
+        match result {
+            Ok(size) => {
+                self.downloaded += 1;
+                self.downloaded_bytes += *size as u64;
+            }
+            Err(DownloadStatus::Skipped()) => self.skipped += 1,
+            Err(DownloadStatus::NotOutdated()) => self.skipped += 1,
+            Err(DownloadStatus::Unchanged()) => self.unchanged += 1,
+            Err(DownloadStatus::HTTPError(id, e)) => {
+                let prefix = format!("{:0>5X}", id);
+                warn!(range = prefix.as_str(), status_code = e.status_code; "range {}: {}", prefix, e);
+                self.error += 1;
+                self.failed.push(FailedRange {
+                    id: *id,
+                    prefix,
+                    status_code: e.status_code,
+                });
+            }
+            Err(_) => self.error += 1,
         }
     }
 }
@@ -135,144 +482,1038 @@ impl Status {
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> ExitCode {
     let args: CliArgs = argh::from_env();
+    let started = Local::now();
+    let start_time = Instant::now();
 
     if args.start > args.end || args.end > MAX_COUNT {
         println!("bad start/end parameters");
-        return ExitCode::from(255);
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
+
+    if let Err(e) = reqwest::Url::parse(&args.base_url) {
+        println!("invalid --base-url {}: {}", args.base_url, e);
+        return ExitCode::from(EXIT_BAD_ARGS);
     }
 
-    let mut status = Status::new(args.end - args.start + 1);
-    let bars = build_progress_meter(&status);
+    if args.hash_type == HashType::Ntlm && (!args.extra_sha1.is_empty() || args.allowlist.is_some())
+    {
+        println!(
+            "--extra-sha1 and --allowlist contain SHA1 hashes and can not be used with --hash-type ntlm"
+        );
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
+
+    if let Err(e) = args.progress_interval() {
+        println!("{}", e);
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
 
-    init_logger(args.log_level(), bars.multi.clone());
+    if let Some(Err(e)) = match &args.command {
+        Some(Command::Daemon(daemon)) => Some(daemon.intervals(&args)),
+        Some(Command::RetryFailed(retry)) => Some(retry.backoffs()),
+        _ => None,
+    } {
+        println!("{}", e);
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
+
+    if args.dry_run && args.command.is_some() {
+        println!("--dry-run is only supported for updates");
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
+    if args.dry_run || matches!(args.command, Some(Command::Status(_) | Command::Show(_))) {
+        let hidden =
+            indicatif::MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        init_logger(args.log_level(), args.log_format, hidden);
+        return ExitCode::from(run_inspect(&args).await);
+    }
+    if let Some(Command::ExportClient(export)) = &args.command {
+        init_logger(
+            args.log_level(),
+            args.log_format,
+            indicatif::MultiProgress::new(),
+        );
+        return ExitCode::from(run_export_client(&args, export).await);
+    }
+
+    let mut total = args.end - args.start + 1;
+    if matches!(args.command, None | Some(Command::Daemon(_))) {
+        total += args.extra_sources().len() as u32;
+    }
+    let mut status = Status::new(total);
+    let bars = build_progress_meter(&status, &args);
+
+    init_logger(args.log_level(), args.log_format, bars.multi.clone());
     let state_db = StateDatabase::open(&args.state_db_path()).await;
     if state_db.is_err() {
         error!(
             "Failed to open sqlite database: {}",
             state_db.err().unwrap()
         );
-        return ExitCode::from(1);
+        return ExitCode::from(EXIT_OPEN_FAILED);
     }
     let state_db = state_db.unwrap();
     if state_db.is_readonly().await {
         error!("Failed to open sqlite database with write permissions.");
-        return ExitCode::from(1);
+        return ExitCode::from(EXIT_OPEN_FAILED);
     }
 
-    let mut exit_code: u8 = 0;
-    let client = Client::new();
+    let Some(allowlist) = open_allowlist(&args) else {
+        return ExitCode::from(EXIT_OPEN_FAILED);
+    };
+    let allowlist = AllowlistState::new(&allowlist, &state_db).await;
+
+    let (exit_code, filter_stats) = match &args.command {
+        None => run_update(&args, &state_db, &allowlist, &mut status, &bars).await,
+        Some(Command::Import(import)) => {
+            run_import(&args, import, &state_db, &allowlist, &mut status, &bars).await
+        }
+        Some(Command::Daemon(daemon)) => {
+            run_daemon(&args, daemon, &state_db, &allowlist, &mut status, &bars).await
+        }
+        Some(Command::RetryFailed(retry)) => {
+            run_retry_failed(&args, retry, &state_db, &allowlist, &mut status, &bars).await
+        }
+        Some(Command::Status(_) | Command::Show(_) | Command::ExportClient(_)) => {
+            unreachable!("handled without opening the database for writing")
+        }
+    };
+    allowlist
+        .remove_stale(&state_db, &filter_stats, &mut status)
+        .await;
+
+    bars.update(&status);
+    bars.finish(&status);
+
+    let mut exit_code = final_exit_code(exit_code, &status, &filter_stats);
+    if state_db.close().await.is_err() {
+        error!("Failed to update state database.");
+        if matches!(exit_code, EXIT_OK | EXIT_PARTIAL) {
+            exit_code = EXIT_IO_ERROR;
+        }
+    }
+    if let Some(path) = &args.summary_json {
+        let summary = Summary {
+            mode: args.command.as_ref().map_or("update", |x| x.name()),
+            exit_code,
+            started: started.to_rfc3339(),
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            status: &status,
+            filter: filter_stats,
+        };
+        if let Err(e) = write_summary(path, &summary) {
+            error!("Failed to write summary to {}: {}", path.display(), e);
+            if matches!(exit_code, EXIT_OK | EXIT_PARTIAL) {
+                exit_code = EXIT_IO_ERROR;
+            }
+        }
+    }
+    ExitCode::from(exit_code)
+}
+
+/// capacity exhaustion takes precedence as it also terminates the worker threads,
+/// followed by errors of the run itself, I/O errors and failed downloads
+fn final_exit_code(exit_code: u8, status: &Status, filter_stats: &Option<FilterStats>) -> u8 {
+    let capacity_exceeded = filter_stats.as_ref().is_some_and(|x| x.capacity_exceeded);
+    let write_error = filter_stats.as_ref().is_some_and(|x| x.write_error);
+    let dropped_lists = filter_stats.as_ref().is_some_and(|x| x.dropped_lists > 0);
+    if capacity_exceeded {
+        EXIT_CAPACITY
+    } else if exit_code != EXIT_OK {
+        exit_code
+    } else if write_error || status.db_errors > 0 {
+        EXIT_IO_ERROR
+    } else if status.error > 0 || dropped_lists {
+        EXIT_PARTIAL
+    } else {
+        EXIT_OK
+    }
+}
+
+fn write_summary(path: &Path, summary: &Summary) -> std::io::Result<()> {
+    let mut json = serde_json::to_string_pretty(summary)?;
+    json.push('\n');
+    if path.as_os_str() == "-" {
+        print!("{}", json);
+        Ok(())
+    } else {
+        std::fs::write(path, json)
+    }
+}
 
+fn open_allowlist(args: &CliArgs) -> Option<Allowlist> {
+    match &args.allowlist {
+        Some(path) => match Allowlist::open(path) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                error!("Failed to read allowlist {}: {}", path.display(), e);
+                None
+            }
+        },
+        None => Some(Allowlist::default()),
+    }
+}
+
+/// runs the status and show commands and --dry-run against a read-only state database
+async fn run_inspect(args: &CliArgs) -> u8 {
+    if args.dry_run && !args.state_db_path().exists() {
+        return print_plan(args, None).await.unwrap_or(EXIT_INTERNAL);
+    }
+    let state_db = match StateDatabase::open_readonly(&args.state_db_path()).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to open sqlite database: {}", e);
+            return EXIT_OPEN_FAILED;
+        }
+    };
+    if !state_db.is_current().await {
+        error!("The state database uses an older schema, run ipwned-builder once to upgrade it.");
+        return EXIT_OPEN_FAILED;
+    }
+    let result = match &args.command {
+        _ if args.dry_run => print_plan(args, Some(&state_db)).await,
+        Some(Command::Show(show)) => print_range(&state_db, &show.prefix).await,
+        _ => print_status(args, &state_db).await,
+    };
+    let _ = state_db.close().await;
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Failed to read state database: {}", e);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+async fn run_export_client(args: &CliArgs, export: &ExportClientArgs) -> u8 {
+    let filter_path = args.filter_path();
+    let mut output = args.base_path.to_owned();
+    output.push(&export.output);
+    let error_rate = export.error_rate;
+    let filter = match PwnedFilter::open(&filter_path) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to open filter {}: {}", filter_path.display(), e);
+            return EXIT_OPEN_FAILED;
+        }
+    };
+    let client = tokio::task::spawn_blocking(move || filter.with_error_rate(error_rate))
+        .await
+        .unwrap();
+    let client = match client {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            error!(
+                "Failed to create client filter with --error-rate {}: {}",
+                error_rate, e
+            );
+            return EXIT_BAD_ARGS;
+        }
+        Err(e) => {
+            error!("Failed to create client filter: {}", e);
+            return EXIT_INTERNAL;
+        }
+    };
+    if !write_filter(&output, &client) {
+        return EXIT_IO_ERROR;
+    }
+    let info = client.info();
+    println!(
+        "{} entries, max error rate {:.2e}, {}",
+        info.entries,
+        info.max_error_rate,
+        indicatif::HumanBytes(info.memory_usage as u64)
+    );
+    EXIT_OK
+}
+
+/// evaluates which ranges an update would download, a missing state database means none were fetched yet
+async fn print_plan(
+    args: &CliArgs,
+    state_db: Option<&StateDatabase>,
+) -> tokio_rusqlite::Result<u8> {
+    let parsed_duration: Duration = parse_duration::parse(&args.max_age).unwrap();
+    let max_age = Local::now().fixed_offset() - TimeDelta::from_std(parsed_duration).unwrap();
+    let Some(allowlist) = open_allowlist(args) else {
+        return Ok(EXIT_OPEN_FAILED);
+    };
+    let (states, forced) = match state_db {
+        Some(state_db) => (
+            state_db.fetch_states(args.start, args.end).await?,
+            AllowlistState::new(&allowlist, state_db)
+                .await
+                .unchecked_ranges,
+        ),
+        None => (Vec::new(), HashSet::new()),
+    };
+    let sizes: Vec<u64> = states.iter().filter_map(|x| x.size).collect();
+    let typical_size = match sizes.len() {
+        0 => TYPICAL_RANGE_SIZE,
+        n => sizes.iter().sum::<u64>() / n as u64,
+    };
+    let mut states: HashMap<u32, State> = states.into_iter().map(|x| (x.id, x)).collect();
+
+    let (mut missing, mut outdated, mut allowlisted, mut skipped) = (0, 0, 0, 0);
+    let mut size: u64 = 0;
+    for id in args.start..=args.end {
+        let state = states.remove(&id);
+        let size_estimate = state.as_ref().and_then(|x| x.size).unwrap_or(typical_size);
+        if state.is_none() {
+            missing += 1;
+        } else if check_db_state(max_age, &Ok(state)) {
+            outdated += 1;
+        } else if forced.contains(&id) {
+            allowlisted += 1;
+        } else {
+            skipped += 1;
+            continue;
+        }
+        size += size_estimate;
+    }
+    println!(
+        "would download: {} ranges ({} never fetched, {} outdated, {} for allowlist checks)",
+        missing + outdated + allowlisted,
+        missing,
+        outdated,
+        allowlisted
+    );
+    println!("would skip:     {} ranges", skipped);
+    println!(
+        "estimated size: {} (upper bound, unchanged ranges are answered with 304 Not Modified)",
+        indicatif::HumanBytes(size)
+    );
+    Ok(EXIT_OK)
+}
+
+async fn print_status(args: &CliArgs, state_db: &StateDatabase) -> tokio_rusqlite::Result<u8> {
+    let parsed_duration: Duration = parse_duration::parse(&args.max_age).unwrap();
+    let max_age = Local::now().fixed_offset() - TimeDelta::from_std(parsed_duration).unwrap();
+    let before = max_age.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string();
+    let summary = state_db.summarize_ranges(args.start, args.end).await?;
+    let stale = state_db
+        .fetch_outdated(args.start, args.end, before)
+        .await?;
+    let failed = state_db.fetch_failures(args.start, args.end).await?;
+    let sources = state_db.fetch_sources().await?;
+    let total = args.end - args.start + 1;
+    println!(
+        "ranges:        {:0>5X}..={:0>5X} ({})",
+        args.start, args.end, total
+    );
+    println!("fetched:       {}", summary.fetched);
+    println!("never fetched: {}", total - summary.fetched);
+    println!(
+        "stale:         {} (older than {})",
+        stale.len(),
+        args.max_age
+    );
+    println!("failed:        {}", failed.len());
+    println!("hashes:        {}", summary.hashes);
+    println!("size:          {} bytes", summary.size);
+    println!(
+        "oldest update: {} UTC",
+        summary.oldest.as_deref().unwrap_or("-")
+    );
+    println!(
+        "newest update: {} UTC",
+        summary.newest.as_deref().unwrap_or("-")
+    );
+    println!("extra sources: {}", sources.len());
+    Ok(EXIT_OK)
+}
+
+async fn print_range(state_db: &StateDatabase, prefix: &str) -> tokio_rusqlite::Result<u8> {
+    let id = match prefix.get(..5).map(|x| u32::from_str_radix(x, 16)) {
+        Some(Ok(x)) if prefix.chars().all(|x| x.is_ascii_hexdigit()) => x,
+        _ => {
+            println!("invalid range prefix {}", prefix);
+            return Ok(EXIT_BAD_ARGS);
+        }
+    };
+    let failure = state_db.fetch_failures(id, id).await?.pop();
+    println!("range:         {:0>5X}", id);
+    match state_db.fetch(id).await? {
+        Some(state) => {
+            let na = || String::from("-");
+            println!("etag:          {}", state.etag.unwrap_or_else(na));
+            println!("last update:   {} UTC", state.last_update);
+            println!("last modified: {}", state.last_modified.unwrap_or_else(na));
+            println!(
+                "status:        {}",
+                state.status.map_or_else(na, |x| x.to_string())
+            );
+            println!(
+                "hashes:        {}",
+                state.hashes.map_or_else(na, |x| x.to_string())
+            );
+            println!(
+                "size:          {}",
+                state.size.map_or_else(na, |x| x.to_string())
+            );
+            println!("digest:        {}", state.digest.unwrap_or_else(na));
+        }
+        None => println!("never fetched"),
+    }
+    if let Some(failure) = failure {
+        println!(
+            "failed:        {} times, last at {} UTC: {}",
+            failure.count, failure.last_attempt, failure.last_error
+        );
+    }
+    Ok(EXIT_OK)
+}
+
+/// ranges and extra sources to download in a single run
+struct UpdatePlan<'a> {
+    ranges: Vec<u32>,
+    sources: Vec<(&'a Path, ListFormat)>,
+    /// ranges last updated after this time are skipped
+    max_age: DateTime<FixedOffset>,
+}
+
+async fn run_update(
+    args: &CliArgs,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+    status: &mut Status,
+    bars: &ProgressBars,
+) -> (u8, Option<FilterStats>) {
     let parsed_duration: Duration = parse_duration::parse(&args.max_age).unwrap();
     let min_file_age_duration: TimeDelta = TimeDelta::from_std(parsed_duration).unwrap();
     let now = Local::now().fixed_offset();
-    let max_age = now - min_file_age_duration;
+    let plan = UpdatePlan {
+        ranges: (args.start..=args.end).collect(),
+        sources: args.extra_sources(),
+        max_age: now - min_file_age_duration,
+    };
+    run_downloads(args, plan, state_db, allowlist, status, bars).await
+}
 
-    {
-        let mut filter_builder =
-            FilterBuilder::new(args.filter_path(), args.max_count, args.max_error_rate);
-        let schedule_downloads = stream::iter(args.start..=args.end)
-            .map(|i| {
-                schedule_download(
-                    i,
-                    &client,
-                    &args.base_url,
-                    args.max_retries,
-                    &filter_builder.in_tx,
-                    &state_db,
-                    max_age,
-                )
-            })
-            .buffer_unordered(args.parallel);
-        pin_mut!(schedule_downloads);
-
-        let mut do_exit = false;
-        loop {
-            let mut update = false;
-            tokio::select! {
-                _ = tokio::signal::ctrl_c() => {
-                    do_exit = true;
-                    break;
-                },
-                x = schedule_downloads.next() => {
-                    if x.is_some() {
-                        let result = x.unwrap();
-                        if !handle_download_status(&result, &mut status, &filter_builder.in_tx).await {
-                            exit_code = 2;
-                            break;
-                        }
-                        update = true;
+async fn run_retry_failed(
+    args: &CliArgs,
+    retry: &RetryFailedArgs,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+    status: &mut Status,
+    bars: &ProgressBars,
+) -> (u8, Option<FilterStats>) {
+    let (backoff, max_backoff) = retry.backoffs().unwrap();
+    let failures = match state_db.fetch_failures(args.start, args.end).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to read state database: {}", e);
+            return (EXIT_IO_ERROR, None);
+        }
+    };
+    let now = Local::now().fixed_offset();
+    let failed = failures.len();
+    let ranges: Vec<u32> = failures
+        .into_iter()
+        .filter(|x| retry_due(x, backoff, max_backoff, now))
+        .map(|x| x.id)
+        .collect();
+    info!(
+        "retrying {} of {} failed ranges, the others are still backing off",
+        ranges.len(),
+        failed
+    );
+    status.total = ranges.len() as u32;
+    if ranges.is_empty() {
+        return (EXIT_OK, None);
+    }
+    // failed ranges are retried regardless of their age
+    let plan = UpdatePlan {
+        ranges,
+        sources: Vec::new(),
+        max_age: now,
+    };
+    run_downloads(args, plan, state_db, allowlist, status, bars).await
+}
+
+/// whether the backoff after the last failed attempt has passed, it doubles with every consecutive failure
+fn retry_due(
+    failure: &Failure,
+    backoff: Duration,
+    max_backoff: Duration,
+    now: DateTime<FixedOffset>,
+) -> bool {
+    let Ok(last_attempt) =
+        NaiveDateTime::parse_from_str(&failure.last_attempt, "%Y-%m-%d %H:%M:%S")
+    else {
+        return true;
+    };
+    let exponent = failure.count.saturating_sub(1).min(31);
+    let wait = backoff.saturating_mul(1 << exponent).min(max_backoff);
+    let wait = TimeDelta::from_std(wait).unwrap_or(TimeDelta::MAX);
+    last_attempt.and_utc().fixed_offset() + wait <= now
+}
+
+async fn run_downloads(
+    args: &CliArgs,
+    plan: UpdatePlan<'_>,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+    status: &mut Status,
+    bars: &ProgressBars,
+) -> (u8, Option<FilterStats>) {
+    let mut exit_code: u8 = EXIT_OK;
+    let client = Client::new();
+    let max_age = plan.max_age;
+
+    let mut filter_builder = match FilterBuilder::new(
+        args.filter_path(),
+        args.max_count,
+        args.max_error_rate,
+        allowlist.active.clone(),
+        allowlist.restore.clone(),
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "Failed to open filter {}: {}",
+                args.filter_path().display(),
+                e
+            );
+            return (EXIT_OPEN_FAILED, None);
+        }
+    };
+    let schedule_sources = stream::iter(plan.sources)
+        .then(|(path, format)| schedule_source(path, format, &filter_builder.in_tx, state_db));
+    let schedule_downloads = stream::iter(plan.ranges)
+        .map(|i| {
+            schedule_download(
+                i,
+                &client,
+                args,
+                &filter_builder.in_tx,
+                state_db,
+                max_age,
+                allowlist.unchecked_ranges.contains(&i),
+            )
+        })
+        .buffer_unordered(args.parallel);
+    let schedule_downloads = schedule_sources.chain(schedule_downloads);
+    pin_mut!(schedule_downloads);
+
+    let mut do_exit = false;
+    loop {
+        let mut update = false;
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                do_exit = true;
+                break;
+            },
+            x = schedule_downloads.next() => {
+                if let Some(result) = x {
+                    if !handle_download_status(&result, status, &filter_builder.in_tx).await {
+                        exit_code = EXIT_INTERNAL;
+                        break;
                     }
-                },
-                x = filter_builder.out_rx.recv() => {
+                    update = true;
+                }
+            },
+            x = filter_builder.out_rx.recv() => {
+                match x {
+                    Some(Some(x)) => {
+                        handle_result(x, status, state_db, allowlist).await;
+                        update = true;
+                    },
+                    _ => break,
+                }
+            }
+        }
+        if update {
+            bars.update(status);
+        }
+    }
+
+    if do_exit {
+        warn!("received ctrl+c, waiting for workers to finish");
+        finish_workers(
+            &filter_builder.in_tx,
+            &mut filter_builder.out_rx,
+            state_db,
+            allowlist,
+            status,
+            bars,
+        )
+        .await;
+    }
+    (exit_code, Some(filter_builder.stats()))
+}
+
+async fn run_import(
+    args: &CliArgs,
+    import: &ImportArgs,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+    status: &mut Status,
+    bars: &ProgressBars,
+) -> (u8, Option<FilterStats>) {
+    let reader = match DumpReader::open(&import.file, args.range_format()).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to open hash dump {}: {}", import.file.display(), e);
+            return (EXIT_OPEN_FAILED, None);
+        }
+    };
+    let mut exit_code: u8 = EXIT_OK;
+
+    let mut filter_builder = match FilterBuilder::new(
+        args.filter_path(),
+        args.max_count,
+        args.max_error_rate,
+        allowlist.active.clone(),
+        allowlist.restore.clone(),
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "Failed to open filter {}: {}",
+                args.filter_path().display(),
+                e
+            );
+            return (EXIT_OPEN_FAILED, None);
+        }
+    };
+    let ranges = stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        match reader.next_range().await {
+            Ok(Some(list)) => Some((Ok(list), Some(reader))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .filter(|list| {
+        let keep = match list {
+            Ok(list) => (args.start..=args.end).contains(&list.id),
+            Err(_) => true,
+        };
+        async move { keep }
+    })
+    .then(|list| schedule_import(list, &filter_builder.in_tx));
+    pin_mut!(ranges);
+
+    let mut reading = true;
+    let mut do_exit = false;
+    loop {
+        let mut update = false;
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                do_exit = true;
+                break;
+            },
+            x = ranges.next(), if reading => {
+                if let Some(Ok(size)) = x {
+                    status.processed += 1;
+                    status.downloaded += 1;
+                    status.downloaded_bytes += size as u64;
+                    update = true;
+                } else {
                     match x {
-                        Some(Some(x)) => {
-                            handle_result(x, &mut status, &state_db).await;
-                            update = true;
-                        },
-                        _ => break,
+                        Some(Err(code)) => exit_code = code,
+                        _ => debug!("successfully read hash dump"),
+                    }
+                    // stop reading, let the worker threads finish what was scheduled so far
+                    reading = false;
+                    if filter_builder.in_tx.send(None).await.is_err() {
+                        error!("INTERNAL: channel to FilterBuilder thread unexpectedly closed");
+                        return (EXIT_INTERNAL, Some(filter_builder.stats()));
                     }
                 }
+            },
+            x = filter_builder.out_rx.recv() => {
+                match x {
+                    Some(Some(x)) => {
+                        handle_result(x, status, state_db, allowlist).await;
+                        update = true;
+                    },
+                    _ => break,
+                }
             }
-            if update {
-                bars.update(&status);
+        }
+        if update {
+            bars.update(status);
+        }
+    }
+
+    if do_exit {
+        warn!("received ctrl+c, waiting for workers to finish");
+        finish_workers(
+            &filter_builder.in_tx,
+            &mut filter_builder.out_rx,
+            state_db,
+            allowlist,
+            status,
+            bars,
+        )
+        .await;
+    }
+    (exit_code, Some(filter_builder.stats()))
+}
+
+async fn run_daemon(
+    args: &CliArgs,
+    daemon: &DaemonArgs,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+    status: &mut Status,
+    bars: &ProgressBars,
+) -> (u8, Option<FilterStats>) {
+    let mut exit_code: u8 = EXIT_OK;
+    let client = Client::new();
+
+    let parsed_duration: Duration = parse_duration::parse(&args.max_age).unwrap();
+    let min_file_age_duration: TimeDelta = TimeDelta::from_std(parsed_duration).unwrap();
+    let (pace, checkpoint_interval) = daemon.intervals(args).unwrap();
+    let mut pace_timer = tokio::time::interval(pace);
+    pace_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut checkpoint_timer = tokio::time::interval(checkpoint_interval);
+    checkpoint_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    checkpoint_timer.tick().await;
+    let mut terminate = Terminate::new();
+
+    let mut filter_builder = match FilterBuilder::new(
+        args.filter_path(),
+        args.max_count,
+        args.max_error_rate,
+        allowlist.active.clone(),
+        allowlist.restore.clone(),
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "Failed to open filter {}: {}",
+                args.filter_path().display(),
+                e
+            );
+            return (EXIT_OPEN_FAILED, None);
+        }
+    };
+    for (path, format) in args.extra_sources() {
+        let result = schedule_source(path, format, &filter_builder.in_tx, state_db).await;
+        status.count_download(&result);
+    }
+
+    // ranges which were never downloaded are fetched right away, outdated ones are paced
+    let mut missing: VecDeque<u32> = VecDeque::new();
+    let mut outdated: VecDeque<u32> = VecDeque::new();
+    let mut last_scan: Option<Instant> = None;
+    let mut max_age = Local::now().fixed_offset() - min_file_age_duration;
+    let mut downloads = FuturesUnordered::new();
+
+    loop {
+        if missing.is_empty()
+            && outdated.is_empty()
+            && downloads.is_empty()
+            && last_scan.is_none_or(|x| x.elapsed() >= RESCAN_INTERVAL)
+        {
+            max_age = Local::now().fixed_offset() - min_file_age_duration;
+            let before = max_age.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string();
+            let fetched: HashSet<u32> = match state_db.fetch_ids(args.start, args.end).await {
+                Ok(x) => x.into_iter().collect(),
+                Err(e) => {
+                    error!("Failed to read state database: {}", e);
+                    exit_code = EXIT_IO_ERROR;
+                    break;
+                }
+            };
+            missing = (args.start..=args.end)
+                .filter(|x| !fetched.contains(x))
+                .collect();
+            outdated = match state_db.fetch_outdated(args.start, args.end, before).await {
+                Ok(x) => x.into(),
+                Err(e) => {
+                    error!("Failed to read state database: {}", e);
+                    exit_code = EXIT_IO_ERROR;
+                    break;
+                }
+            };
+            info!(
+                "scheduling {} new and {} outdated ranges",
+                missing.len(),
+                outdated.len()
+            );
+            status.total = (missing.len() + outdated.len()) as u32;
+            status.processed = 0;
+            last_scan = Some(Instant::now());
+        }
+        while downloads.len() < args.parallel {
+            match missing.pop_front() {
+                Some(i) => downloads.push(schedule_download(
+                    i,
+                    &client,
+                    args,
+                    &filter_builder.in_tx,
+                    state_db,
+                    max_age,
+                    allowlist.unchecked_ranges.contains(&i),
+                )),
+                None => break,
             }
         }
 
-        if do_exit {
-            // we received a ctrl+c, process all downloaded files and terminate
-            warn!("received ctrl+c, waiting for workers to finish");
-            // signal our worker threads to exit
-            if filter_builder.in_tx.send(None).await.is_ok() {
-                // if something went wrong we can just quit, otherwise wait for everything to finish
-                loop {
-                    match filter_builder.out_rx.recv().await {
-                        Some(Some(x)) => {
-                            handle_result(x, &mut status, &state_db).await;
-                            bars.update(&status);
-                        }
-                        _ => break,
+        let mut update = false;
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+            _ = pace_timer.tick() => {
+                if let Some(i) = outdated.pop_front() {
+                    downloads.push(schedule_download(
+                        i,
+                        &client,
+                        args,
+                        &filter_builder.in_tx,
+                        state_db,
+                        max_age,
+                        allowlist.unchecked_ranges.contains(&i),
+                    ));
+                }
+            },
+            Some(result) = downloads.next(), if !downloads.is_empty() => {
+                status.count_download(&result);
+                update = true;
+            },
+            _ = checkpoint_timer.tick() => {
+                if filter_builder.checkpoint().await {
+                    notify_server(&daemon.notify_pid_file);
+                }
+            },
+            x = filter_builder.out_rx.recv() => {
+                match x {
+                    Some(Some(x)) => {
+                        handle_result(x, status, state_db, allowlist).await;
+                        update = true;
+                    },
+                    _ => {
+                        error!("INTERNAL: FilterBuilder thread unexpectedly exited");
+                        exit_code = EXIT_INTERNAL;
+                        break;
                     }
                 }
             }
         }
+        if update {
+            bars.update(status);
+        }
     }
 
-    bars.update(&status);
-    bars.finish();
+    warn!("stopping daemon, waiting for workers to finish");
+    drop(downloads);
+    let writes = filter_builder.writes();
+    finish_workers(
+        &filter_builder.in_tx,
+        &mut filter_builder.out_rx,
+        state_db,
+        allowlist,
+        status,
+        bars,
+    )
+    .await;
+    if filter_builder.writes() > writes {
+        notify_server(&daemon.notify_pid_file);
+    }
+    (exit_code, Some(filter_builder.stats()))
+}
 
-    if state_db.close().await.is_err() {
-        error!("Failed to update state database.");
-        exit_code = 3;
+/// sends SIGHUP to the ipwned-server process in the given pid file to make it reload the filter
+fn notify_server(pid_file: &Option<PathBuf>) {
+    let Some(pid_file) = pid_file else {
+        return;
+    };
+    let pid = std::fs::read_to_string(pid_file)
+        .ok()
+        .and_then(|x| x.trim().parse::<i32>().ok());
+    let Some(pid) = pid else {
+        warn!(
+            "unable to read ipwned-server pid from {}",
+            pid_file.display()
+        );
+        return;
+    };
+    send_sighup(pid);
+}
+
+#[cfg(unix)]
+fn send_sighup(pid: i32) {
+    if unsafe { libc::kill(pid, libc::SIGHUP) } != 0 {
+        warn!(
+            "failed to notify ipwned-server with pid {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    } else {
+        info!(
+            "notified ipwned-server with pid {} to reload the filter",
+            pid
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn send_sighup(pid: i32) {
+    warn!(
+        "unable to notify ipwned-server with pid {}, signals are not supported on this platform",
+        pid
+    );
+}
+
+/// SIGTERM on unix, platforms without it only stop on ctrl+c
+struct Terminate {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Terminate {
+    fn new() -> Terminate {
+        Terminate {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .unwrap(),
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn finish_workers(
+    in_tx: &Sender<Option<HashList>>,
+    out_rx: &mut Receiver<Option<FilterResult>>,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+    status: &mut Status,
+    bars: &ProgressBars,
+) {
+    // process all downloaded files and terminate
+    // signal our worker threads to exit
+    if in_tx.send(None).await.is_ok() {
+        // if something went wrong we can just quit, otherwise wait for everything to finish
+        while let Some(Some(x)) = out_rx.recv().await {
+            handle_result(x, status, state_db, allowlist).await;
+            bars.update(status);
+        }
     }
-    ExitCode::from(exit_code)
 }
 
 struct ProgressBars {
     pub multi: indicatif::MultiProgress,
     overview: indicatif::ProgressBar,
     bar: indicatif::ProgressBar,
+    /// replaces the bars when running non-interactively
+    lines: Option<ProgressLines>,
+}
+
+/// periodic progress reports for logs, e.g. when running from systemd or cron
+struct ProgressLines {
+    format: LogFormat,
+    interval: Duration,
+    started: Instant,
+    last: Cell<Instant>,
+}
+
+impl ProgressLines {
+    pub fn print(&self, status: &Status) {
+        self.last.set(Instant::now());
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0. {
+            status.processed as f64 / elapsed
+        } else {
+            0.
+        };
+        let remaining = status.total.saturating_sub(status.processed);
+        let eta = (rate > 0.).then(|| (remaining as f64 / rate).round() as u64);
+        match self.format {
+            LogFormat::Text => eprintln!(
+                "{} progress processed={} total={} rate={:.1} eta_secs={} hashes={} hashes_new={} skipped={} unchanged={} downloaded={} downloaded_bytes={} errors={}",
+                Local::now().format("%H:%M:%S"),
+                status.processed,
+                status.total,
+                rate,
+                eta.map_or(String::from("unknown"), |x| x.to_string()),
+                status.hashes,
+                status.hashes_new,
+                status.skipped,
+                status.unchanged,
+                status.downloaded,
+                status.downloaded_bytes,
+                status.error
+            ),
+            LogFormat::Json => {
+                write_json_line(&serde_json::json!({
+                    "ts": Local::now().to_rfc3339(),
+                    "level": "INFO",
+                    "target": "progress",
+                    "msg": "progress",
+                    "processed": status.processed,
+                    "total": status.total,
+                    "rate": rate,
+                    "eta_secs": eta,
+                    "hashes": status.hashes,
+                    "hashes_new": status.hashes_new,
+                    "skipped": status.skipped,
+                    "unchanged": status.unchanged,
+                    "downloaded": status.downloaded,
+                    "downloaded_bytes": status.downloaded_bytes,
+                    "errors": status.error,
+                }));
+            }
+        }
+    }
 }
 
 impl ProgressBars {
     pub fn update(&self, status: &Status) {
         let msg = format!(
-            "{}/{},  skipped: {}, downloaded: {}, errors: {}",
-            status.hashes_new, status.hashes, status.skipped, status.downloaded, status.error
+            "{}/{},  skipped: {}, unchanged: {}, downloaded: {}, errors: {}",
+            status.hashes_new,
+            status.hashes,
+            status.skipped,
+            status.unchanged,
+            status.downloaded,
+            status.error
         );
         self.overview.set_length(status.downloaded_bytes);
         self.overview.set_position(status.downloaded_bytes);
         self.overview.set_message(msg);
+        self.bar.set_length(status.total as u64);
         self.bar.set_position(status.processed as u64);
+        let due = self
+            .lines
+            .as_ref()
+            .filter(|x| x.last.get().elapsed() >= x.interval);
+        if let Some(lines) = due {
+            lines.print(status);
+        }
     }
 
-    pub fn finish(&self) {
+    pub fn finish(&self, status: &Status) {
         self.overview.finish();
         self.bar.finish();
+        if let Some(lines) = &self.lines {
+            lines.print(status);
+        }
     }
 }
 
-fn build_progress_meter(status: &Status) -> ProgressBars {
-    let m = indicatif::MultiProgress::new();
+fn build_progress_meter(status: &Status, args: &CliArgs) -> ProgressBars {
+    let interactive = std::io::stderr().is_terminal() && args.log_format == LogFormat::Text;
+    let m = if interactive {
+        indicatif::MultiProgress::new()
+    } else {
+        indicatif::MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden())
+    };
+    let lines = (!interactive).then(|| ProgressLines {
+        format: args.log_format,
+        interval: args.progress_interval().unwrap(),
+        started: Instant::now(),
+        last: Cell::new(Instant::now()),
+    });
     let overview = m.add(indicatif::ProgressBar::new(0));
     let bar = m.add(indicatif::ProgressBar::new(status.total as u64));
     let overview_style = indicatif::ProgressStyle::with_template(
@@ -293,48 +1534,99 @@ fn build_progress_meter(status: &Status) -> ProgressBars {
     overview.set_style(overview_style);
     ProgressBars {
         multi: m,
-        overview: overview,
-        bar: bar,
+        overview,
+        bar,
+        lines,
     }
 }
 
-fn init_logger(level: LevelFilter, multibar: indicatif::MultiProgress) {
-    let logger = simplelog::SimpleLogger::new(level, simplelog::Config::default());
-    LogWrapper::new(multibar.clone(), logger)
-        .try_init()
-        .unwrap();
+fn init_logger(level: LevelFilter, format: LogFormat, multibar: indicatif::MultiProgress) {
+    match format {
+        LogFormat::Text => {
+            let logger = simplelog::SimpleLogger::new(level, simplelog::Config::default());
+            LogWrapper::new(multibar.clone(), logger).try_init()
+        }
+        LogFormat::Json => LogWrapper::new(multibar.clone(), JsonLogger::new(level)).try_init(),
+    }
+    .unwrap();
 }
 
 async fn schedule_download(
     hash_list_id: u32,
     client: &Client,
-    base_url: &String,
-    max_retries: u16,
+    args: &CliArgs,
     hash_list_chan: &Sender<Option<HashList>>,
     state_db: &StateDatabase,
     max_age: DateTime<FixedOffset>,
+    force: bool,
 ) -> Result<usize, DownloadStatus> {
     let state = state_db.fetch(hash_list_id).await;
-    let mut etag: Option<String> = None;
-    let need_update = check_db_state(max_age, &mut etag, state);
-    if !need_update {
+    let need_update = check_db_state(max_age, &state);
+    let (mut etag, digest) = match state {
+        Ok(Some(x)) => (x.etag.as_deref().map(normalize_etag), x.digest),
+        _ => (None, None),
+    };
+    if force {
+        // the full list is required, even if it has not changed
+        etag = None;
+    } else if !need_update {
         return Err(DownloadStatus::Skipped {});
     }
     let hash_prefix = format!("{:0>5X}", hash_list_id);
-    let res = download_retry(client, base_url, &hash_prefix, etag, max_retries)
-        .await
-        .map_err(|err: DownloadError| {
-            if err.status_code.unwrap_or(0_u16) == 304_u16 {
-                return DownloadStatus::NotOutdated {};
-            }
-            DownloadStatus::HTTPError(err)
-        })?;
+    let res = download_retry(
+        client,
+        &args.base_url,
+        &hash_prefix,
+        args.hash_type,
+        etag.clone(),
+        args.max_retries,
+    )
+    .await
+    .map_err(|err: DownloadError| {
+        if err.status_code.unwrap_or(0_u16) == 304_u16 {
+            return DownloadStatus::NotOutdated {};
+        }
+        DownloadStatus::HTTPError(hash_list_id, err)
+    });
+    if let Err(DownloadStatus::NotOutdated()) = res {
+        // the stored list is still current, reset its age
+        if !state_db.mark_unchanged(hash_list_id, etag, 304).await {
+            error!("failed to update state db for id {}", hash_list_id);
+        }
+    }
+    if let Err(DownloadStatus::HTTPError(_, err)) = &res {
+        let error = err.to_string();
+        if !state_db
+            .record_failure(hash_list_id, err.status_code, error)
+            .await
+        {
+            error!("failed to record download failure of id {}", hash_list_id);
+        }
+    }
+    let res = res?;
     let data_len = res.data.len();
+    let new_digest = faster_hex::hex_string(&Sha1::digest(&res.data));
+    if !force && digest.as_ref() == Some(&new_digest) {
+        // e.g. the etag changed without a change of content, no need to parse the list again
+        if !state_db.mark_unchanged(hash_list_id, res.etag, 200).await {
+            error!("failed to update state db for id {}", hash_list_id);
+        }
+        return Err(DownloadStatus::Unchanged {});
+    }
+    let meta = ListMeta {
+        etag: res.etag,
+        digest: Some(new_digest),
+        size: data_len as u64,
+        status: Some(200),
+        last_modified: res.last_modified,
+    };
     if hash_list_chan
         .send(Some(HashList {
             id: hash_list_id,
             data: res.data,
-            etag: res.etag,
+            meta,
+            format: args.range_format(),
+            reject_partial: false,
         }))
         .await
         .is_err()
@@ -345,24 +1637,85 @@ async fn schedule_download(
     Ok(data_len)
 }
 
+async fn schedule_source(
+    path: &Path,
+    format: ListFormat,
+    hash_list_chan: &Sender<Option<HashList>>,
+    state_db: &StateDatabase,
+) -> Result<usize, DownloadStatus> {
+    let name = std::fs::canonicalize(path)
+        .unwrap_or(path.to_owned())
+        .to_string_lossy()
+        .into_owned();
+    let data = tokio::fs::read(path).await.map_err(|e| {
+        error!("Failed to read extra source {}: {}", name, e);
+        DownloadStatus::InternalError {}
+    })?;
+    let digest = faster_hex::hex_string(&Sha1::digest(&data));
+    let state = state_db.fetch_source(name.clone()).await;
+    if matches!(state, Ok(Some(source))
+        if source.kind == format.as_str() && source.digest.as_ref() == Some(&digest))
+    {
+        debug!("extra source {} is unchanged", name);
+        return Err(DownloadStatus::NotOutdated {});
+    }
+    let id = state_db
+        .register_source(name, format.as_str().to_string())
+        .await
+        .map_err(|e| {
+            error!("Failed to register extra source: {}", e);
+            DownloadStatus::InternalError {}
+        })?;
+    let data_len = data.len();
+    if hash_list_chan
+        .send(Some(HashList {
+            id,
+            data: data.into(),
+            meta: ListMeta {
+                digest: Some(digest),
+                size: data_len as u64,
+                ..Default::default()
+            },
+            format,
+            reject_partial: true,
+        }))
+        .await
+        .is_err()
+    {
+        error!("INTERNAL: unexpectedly terminated FilterBuilder main channel");
+        return Err(DownloadStatus::InternalError {});
+    }
+    Ok(data_len)
+}
+
+/// forwards a hash list read from a dump to the FilterBuilder, returns the exit code on failure
+async fn schedule_import(
+    list: std::io::Result<HashList>,
+    hash_list_chan: &Sender<Option<HashList>>,
+) -> Result<usize, u8> {
+    let list = list.map_err(|e| {
+        error!("Failed to read hash dump: {}", e);
+        EXIT_IO_ERROR
+    })?;
+    let data_len = list.data.len();
+    if hash_list_chan.send(Some(list)).await.is_err() {
+        error!("INTERNAL: unexpectedly terminated FilterBuilder main channel");
+        return Err(EXIT_INTERNAL);
+    }
+    Ok(data_len)
+}
+
 fn check_db_state(
     max_age: DateTime<FixedOffset>,
-    etag: &mut Option<String>,
-    state: Result<Option<State>, tokio_rusqlite::Error>,
+    state: &Result<Option<State>, tokio_rusqlite::Error>,
 ) -> bool {
-    let mut need_update = true;
-    if state.is_ok() {
-        if let Some(state) = state.unwrap() {
-            if let Ok(time) = NaiveDateTime::parse_from_str(&state.last_update, "%Y-%m-%d %H:%M:%S") {
-                let time = time.and_utc().fixed_offset();
-                need_update = max_age > time;
-            }
-            if state.etag.is_some() {
-                *etag = Some(state.etag.unwrap());
-            }
-        }
+    let Ok(Some(state)) = state else {
+        return true;
+    };
+    match NaiveDateTime::parse_from_str(&state.last_update, "%Y-%m-%d %H:%M:%S") {
+        Ok(time) => max_age > time.and_utc().fixed_offset(),
+        Err(_) => true,
     }
-    need_update
 }
 
 async fn handle_download_status(
@@ -370,16 +1723,7 @@ async fn handle_download_status(
     status: &mut Status,
     done_channel: &Sender<Option<HashList>>,
 ) -> bool {
-    status.processed += 1;
-    match result {
-        Ok(size) => {
-            status.downloaded += 1;
-            status.downloaded_bytes += *size as u64;
-        }
-        Err(DownloadStatus::Skipped()) => status.skipped += 1,
-        Err(DownloadStatus::NotOutdated()) => status.skipped += 1,
-        Err(_) => status.error += 1,
-    }
+    status.count_download(result);
     if status.processed == status.total {
         if done_channel.send(None).await.is_err() {
             error!("INTERNAL: channel to FilterBuilder thread unexpectedly closed");
@@ -391,10 +1735,109 @@ async fn handle_download_status(
     true
 }
 
-async fn handle_result(result: FilterResult, status: &mut Status, state_db: &StateDatabase) {
+async fn handle_result(
+    result: FilterResult,
+    status: &mut Status,
+    state_db: &StateDatabase,
+    allowlist: &AllowlistState,
+) {
     status.hashes += result.total;
     status.hashes_new += result.added;
-    if !state_db.update(result.id, result.etag).await {
-        error!("failed to update state db for id {}", result.id);
+    if !result.format.is_range() {
+        if !state_db
+            .update_source(result.id, result.meta.digest, result.total)
+            .await
+        {
+            error!("failed to update state db for extra source {}", result.id);
+            status.db_errors += 1;
+        }
+    } else {
+        let update = RangeUpdate {
+            etag: result.meta.etag,
+            hashes: result.total,
+            size: result.meta.size,
+            digest: result.meta.digest,
+            status: result.meta.status,
+            last_modified: result.meta.last_modified,
+        };
+        if !state_db.update_range(result.id, update).await {
+            error!(range = format!("{:0>5X}", result.id).as_str(); "failed to update state db for id {}", result.id);
+            status.db_errors += 1;
+        }
+        // the full range list was processed, any entry of this range not found in it is not in the filter
+        for hash in allowlist.by_range.get(&result.id).into_iter().flatten() {
+            if !result.subtracted.contains(hash)
+                && !state_db.set_allowlisted(hash.to_vec(), false).await
+            {
+                error!("failed to update allowlist state in state db");
+                status.db_errors += 1;
+            }
+        }
+    }
+    for hash in result.subtracted {
+        if !state_db.set_allowlisted(hash.to_vec(), true).await {
+            error!("failed to update allowlist state in state db");
+            status.db_errors += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_stale_allowlist_entries_without_builder() {
+        let dir = std::env::temp_dir().join(format!("ipwned_builder_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let args = CliArgs::from_args(
+            &["ipwned-builder"],
+            &["-d", dir.to_str().unwrap(), "retry-failed"],
+        )
+        .unwrap();
+        let Some(Command::RetryFailed(retry)) = &args.command else {
+            unreachable!();
+        };
+        let state_db = StateDatabase::open(&args.state_db_path()).await.unwrap();
+        // subtracted from the filter by an earlier run, its allowlist entry was removed since
+        let hash = vec![0xab; 20];
+        assert!(state_db.set_allowlisted(hash.clone(), true).await);
+        let allowlist = AllowlistState::new(&Allowlist::default(), &state_db).await;
+        assert_eq!(allowlist.restore.len(), 1);
+        let mut status = Status::new(0);
+        let bars = build_progress_meter(&status, &args);
+
+        // no failed ranges are due, so no filter builder runs
+        let (exit_code, filter_stats) =
+            run_retry_failed(&args, retry, &state_db, &allowlist, &mut status, &bars).await;
+        assert_eq!(exit_code, EXIT_OK);
+        allowlist
+            .remove_stale(&state_db, &filter_stats, &mut status)
+            .await;
+        assert_eq!(
+            state_db.fetch_allowlisted().await.unwrap(),
+            vec![(hash.clone(), true)]
+        );
+
+        // once the hash was added back and the filter written the entry is forgotten
+        let mut filter_builder = FilterBuilder::new(
+            args.filter_path(),
+            1000,
+            0.001,
+            allowlist.active.clone(),
+            allowlist.restore.clone(),
+        )
+        .unwrap();
+        filter_builder.in_tx.send(None).await.unwrap();
+        while let Some(Some(_)) = filter_builder.out_rx.recv().await {}
+        let filter_stats = Some(filter_builder.stats());
+        assert_eq!(filter_stats.as_ref().unwrap().writes, 1);
+        allowlist
+            .remove_stale(&state_db, &filter_stats, &mut status)
+            .await;
+        assert!(state_db.fetch_allowlisted().await.unwrap().is_empty());
+        assert_eq!(status.db_errors, 0);
+        state_db.close().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,301 @@
+use argh::FromArgs;
+use ipwned_localdb::PwnedFilter;
+use ipwned_localdb::allowlist::Allowlist;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const EXIT_NOT_PWNED: u8 = 0;
+const EXIT_PWNED: u8 = 1;
+const EXIT_ERROR: u8 = 2;
+const EXIT_BAD_ARGS: u8 = 255;
+
+#[derive(FromArgs)]
+/// check passwords against a local haveibeenpwned.com password lookup table without running ipwned-server
+struct CliArgs {
+    /// file name of the lookup filter file. default: ./ipwned_qfilter.cbor
+    #[argh(option, short = 'f', default = "String::from(\"ipwned_qfilter.cbor\")")]
+    filter_path: String,
+
+    /// allowlist of hashes that are never reported as pwned, see Readme for the file format. default: none
+    #[argh(option, short = 'a')]
+    allowlist: Option<PathBuf>,
+
+    /// read one password per line from stdin instead of prompting for a single one
+    #[argh(switch)]
+    stdin: bool,
+
+    /// file with one hex encoded SHA1 hash per line, optionally followed by :COUNT, - for stdin
+    #[argh(option)]
+    sha1_file: Option<PathBuf>,
+
+    /// do not print anything, only report the result in the exit code
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+}
+
+/// results are written to `out`, stdout outside of the tests
+struct Query<W: Write> {
+    filter: PwnedFilter,
+    allowlist: Allowlist,
+    quiet: bool,
+    out: W,
+    pwned: bool,
+    errors: bool,
+}
+
+impl<W: Write> Query<W> {
+    fn check(&mut self, hash: &[u8; 20], label: &str) {
+        let pwned = !self.allowlist.contains(hash) && self.filter.contains(hash);
+        self.pwned |= pwned;
+        if self.quiet {
+            return;
+        }
+        let result = if pwned { "pwned" } else { "not pwned" };
+        if let Err(e) = writeln!(self.out, "{}{}", label, result) {
+            self.error(format!("unable to write result: {}", e));
+        }
+    }
+
+    fn check_password(&mut self, password: &str, label: &str) {
+        let hash: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+        self.check(&hash, label);
+    }
+
+    fn error(&mut self, msg: String) {
+        eprintln!("{}", msg);
+        self.errors = true;
+    }
+
+    /// a pwned password takes precedence over errors, the passwords after an unreadable line are not checked
+    fn exit_code(&self) -> u8 {
+        match (self.pwned, self.errors) {
+            (true, _) => EXIT_PWNED,
+            (false, true) => EXIT_ERROR,
+            (false, false) => EXIT_NOT_PWNED,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: CliArgs = argh::from_env();
+    if args.stdin && args.sha1_file.is_some() {
+        eprintln!("--stdin and --sha1-file can not be combined");
+        return ExitCode::from(EXIT_BAD_ARGS);
+    }
+    let filter = match PwnedFilter::open(&args.filter_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("unable to read filter file {}: {}", args.filter_path, e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let allowlist = match &args.allowlist {
+        Some(path) => match Allowlist::open(path) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("unable to read allowlist {}: {}", path.display(), e);
+                return ExitCode::from(EXIT_ERROR);
+            }
+        },
+        None => Allowlist::default(),
+    };
+    let mut query = Query {
+        filter,
+        allowlist,
+        quiet: args.quiet,
+        out: std::io::stdout().lock(),
+        pwned: false,
+        errors: false,
+    };
+    match &args.sha1_file {
+        Some(path) => check_sha1_file(&mut query, path),
+        None if args.stdin => {
+            if std::io::stdin().is_terminal() {
+                eprintln!("reading passwords from stdin, one per line");
+            }
+            check_lines(&mut query, std::io::stdin().lock())
+        }
+        None => check_prompt(&mut query),
+    }
+    ExitCode::from(query.exit_code())
+}
+
+fn check_prompt<W: Write>(query: &mut Query<W>) {
+    match rpassword::prompt_password("Password: ") {
+        Ok(password) => query.check_password(&password, ""),
+        Err(e) => query.error(format!("unable to read password: {}", e)),
+    }
+}
+
+/// prints the result for every line without repeating the password
+fn check_lines<W: Write>(query: &mut Query<W>, reader: impl BufRead) {
+    for (i, line) in reader.lines().enumerate() {
+        match line {
+            Ok(line) => {
+                // files written on windows end lines with \r\n
+                let password = line.strip_suffix('\r').unwrap_or(&line);
+                query.check_password(password, &format!("line {}: ", i + 1));
+            }
+            Err(e) => {
+                query.error(format!("unable to read line {}: {}", i + 1, e));
+                return;
+            }
+        }
+    }
+}
+
+fn check_sha1_file<W: Write>(query: &mut Query<W>, path: &PathBuf) {
+    let reader: Box<dyn BufRead> = if path.as_os_str() == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        match File::open(path) {
+            Ok(x) => Box::new(BufReader::new(x)),
+            Err(e) => {
+                query.error(format!("unable to open {}: {}", path.display(), e));
+                return;
+            }
+        }
+    };
+    check_sha1_lines(query, reader);
+}
+
+/// prints the result for every hash along with it
+fn check_sha1_lines<W: Write>(query: &mut Query<W>, reader: impl BufRead) {
+    let mut hash = [0u8; 20];
+    for (i, line) in reader.lines().enumerate() {
+        let line = match line {
+            Ok(x) => x,
+            Err(e) => {
+                query.error(format!("unable to read line {}: {}", i + 1, e));
+                return;
+            }
+        };
+        let hex = line
+            .split_once(':')
+            .map_or(line.as_str(), |(hex, _)| hex)
+            .trim();
+        if hex.is_empty() {
+            continue;
+        }
+        if hex.len() != 40 || faster_hex::hex_decode(hex.as_bytes(), &mut hash).is_err() {
+            query.error(format!("line {}: not a SHA1 hash", i + 1));
+            continue;
+        }
+        query.check(&hash, &format!("{} ", hex));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PWNED: &str = "5baa61e4c9b93f3f0682250b6cf8331b7ee68fd8";
+    const ALLOWED: &str = "7c4a8d09ca3762af61e59520943dc26494f8941b";
+    const NOT_PWNED: &str = "0000000000000000000000000000000000000000";
+
+    /// query of a filter with the SHA1 hashes of "password" and "123456", the latter allowlisted
+    fn query(quiet: bool) -> Query<Vec<u8>> {
+        let mut filter = qfilter::Filter::new(64, 1e-6).unwrap();
+        for hex in [PWNED, ALLOWED] {
+            let mut hash = [0u8; 20];
+            faster_hex::hex_decode(hex.as_bytes(), &mut hash).unwrap();
+            filter.insert(&hash[..]).unwrap();
+        }
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&filter, &mut cbor).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "ipwned_query_allowlist_test_{}_{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, format!("{} 9999-12-31 test\n", ALLOWED)).unwrap();
+        let allowlist = Allowlist::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        Query {
+            filter: PwnedFilter::from_reader(&cbor[..]).unwrap(),
+            allowlist,
+            quiet,
+            out: Vec::new(),
+            pwned: false,
+            errors: false,
+        }
+    }
+
+    fn output(query: &Query<Vec<u8>>) -> &str {
+        std::str::from_utf8(&query.out).unwrap()
+    }
+
+    #[test]
+    fn passwords() {
+        let mut query = query(false);
+        check_lines(&mut query, &b"not pwned\r\n123456\n\npassword"[..]);
+        assert_eq!(
+            output(&query),
+            "line 1: not pwned\nline 2: not pwned\nline 3: not pwned\nline 4: pwned\n"
+        );
+        assert_eq!(query.exit_code(), EXIT_PWNED);
+
+        let mut query = self::query(false);
+        query.check_password("not pwned", "");
+        assert_eq!(output(&query), "not pwned\n");
+        assert_eq!(query.exit_code(), EXIT_NOT_PWNED);
+    }
+
+    #[test]
+    fn sha1_lines() {
+        let mut query = query(false);
+        let input = format!(
+            "{}:3\r\n\n  {} \n{}\n{}:1:2\n",
+            NOT_PWNED,
+            ALLOWED,
+            PWNED.to_uppercase(),
+            NOT_PWNED
+        );
+        check_sha1_lines(&mut query, input.as_bytes());
+        let expected = format!(
+            "{} not pwned\n{} not pwned\n{} pwned\n{} not pwned\n",
+            NOT_PWNED,
+            ALLOWED,
+            PWNED.to_uppercase(),
+            NOT_PWNED
+        );
+        assert_eq!(output(&query), expected);
+        assert_eq!(query.exit_code(), EXIT_PWNED);
+        assert!(!query.errors);
+    }
+
+    #[test]
+    fn exit_code() {
+        let mut query = query(true);
+        check_sha1_lines(&mut query, format!("{}\n", NOT_PWNED).as_bytes());
+        assert_eq!(query.exit_code(), EXIT_NOT_PWNED);
+        check_sha1_lines(&mut query, &b"not a hash\n:3\n"[..]);
+        assert_eq!(query.exit_code(), EXIT_ERROR);
+        // an invalid line doesn't stop the check, a pwned hash after it takes precedence
+        check_sha1_lines(&mut query, format!("{}\n", PWNED).as_bytes());
+        assert_eq!(query.exit_code(), EXIT_PWNED);
+        assert!(query.errors);
+        assert!(query.out.is_empty());
+    }
+
+    #[test]
+    fn unreadable_line() {
+        // lines that are not UTF-8 fail to read, the lines after them are not checked
+        let mut query = query(false);
+        let mut input = format!("{}\n", NOT_PWNED).into_bytes();
+        input.extend(b"\xff\n");
+        input.extend(format!("{}\n", PWNED).as_bytes());
+        check_sha1_lines(&mut query, &input[..]);
+        assert_eq!(output(&query), format!("{} not pwned\n", NOT_PWNED));
+        assert_eq!(query.exit_code(), EXIT_ERROR);
+
+        let mut query = self::query(false);
+        check_lines(&mut query, &b"password\n\xff\n"[..]);
+        assert_eq!(output(&query), "line 1: pwned\n");
+        assert!(query.errors);
+        assert_eq!(query.exit_code(), EXIT_PWNED);
+    }
+}
@@ -0,0 +1,452 @@
+//! API keys and rate limits of the query routes, configured in a TOML file given with --api-keys.
+
+use rocket::Request;
+use rocket::figment::Figment;
+use rocket::figment::providers::{Format, Toml};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::SharedLookup;
+
+/// maximum number of per IP buckets, the least recently used one is dropped for a new IP above it
+const MAX_IP_BUCKETS: usize = 65536;
+
+/// client names in the metrics of requests without an API key, with a key that is not configured
+pub const ANONYMOUS: &str = "anonymous";
+pub const UNAUTHORIZED: &str = "unauthorized";
+
+/// token bucket settings, requests without a rate are not limited
+#[derive(Deserialize, Clone, Copy, Default)]
+pub struct RateLimit {
+    /// requests per second
+    rate: Option<f64>,
+    /// requests allowed at once after being idle. default: rate, at least 1
+    burst: Option<f64>,
+}
+
+impl RateLimit {
+    fn burst(&self) -> f64 {
+        self.burst.or(self.rate).unwrap_or(1.).max(1.)
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeyConfig {
+    key: String,
+    #[serde(flatten)]
+    limit: RateLimit,
+}
+
+#[derive(Deserialize)]
+struct ApiKeysFile {
+    /// limit per client IP of requests without a key, they are rejected if missing
+    anonymous: Option<RateLimit>,
+    #[serde(default)]
+    keys: HashMap<String, ApiKeyConfig>,
+}
+
+/// API keys by the SHA1 hash of the key, so the key itself is not compared byte by byte
+pub struct ApiKeys {
+    anonymous: Option<RateLimit>,
+    keys: HashMap<[u8; 20], (String, RateLimit)>,
+}
+
+impl ApiKeys {
+    pub fn open(path: &Path) -> Result<ApiKeys, String> {
+        Self::from_figment(Figment::from(Toml::file_exact(path)))
+    }
+
+    #[cfg(test)]
+    pub fn from_toml(toml: &str) -> Result<ApiKeys, String> {
+        Self::from_figment(Figment::from(Toml::string(toml)))
+    }
+
+    fn from_figment(figment: Figment) -> Result<ApiKeys, String> {
+        let file: ApiKeysFile = figment
+            .extract()
+            .map_err(|e| format!("unable to read API keys: {}", e))?;
+        let mut keys = HashMap::new();
+        for (name, config) in file.keys {
+            if config.key.is_empty() {
+                return Err(format!("API key {} is empty", name));
+            }
+            if keys
+                .insert(hash_key(&config.key), (name.clone(), config.limit))
+                .is_some()
+            {
+                return Err(format!("API key {} is used by another name", name));
+            }
+        }
+        Ok(ApiKeys {
+            anonymous: file.anonymous,
+            keys,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// hash of the key if it is configured, identifies the key across reloads
+    pub fn find(&self, key: &[u8]) -> Option<[u8; 20]> {
+        let hash = hash_key(key);
+        self.keys.contains_key(&hash).then_some(hash)
+    }
+
+    /// name of the key with the hash, `None` if it is no longer configured
+    pub fn name(&self, hash: &[u8; 20]) -> Option<&str> {
+        self.keys.get(hash).map(|(name, _)| name.as_str())
+    }
+
+    /// most tokens `take` can succeed with for the key with the hash, `None` if it has no rate limit or is no longer
+    /// configured
+    pub fn burst(&self, hash: &[u8; 20]) -> Option<u32> {
+        let (_, limit) = self.keys.get(hash)?;
+        limit.rate.map(|_| limit.burst() as u32)
+    }
+
+    /// takes `tokens` from the bucket of the key with the hash, `None` if the key is no longer configured. more tokens
+    /// than the burst of the key are never available
+    pub fn take(&self, limiter: &RateLimiter, hash: &[u8; 20], tokens: u32) -> Option<bool> {
+        let (name, limit) = self.keys.get(hash)?;
+        Some(limiter.take(BucketId::Key(name.clone()), *limit, tokens))
+    }
+}
+
+fn hash_key<K: AsRef<[u8]>>(key: K) -> [u8; 20] {
+    Sha1::digest(key).into()
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum BucketId {
+    Key(String),
+    Ip(IpAddr),
+    /// anonymous requests without a client IP, on the unix socket
+    Local,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<BucketId, Bucket>,
+    /// the per IP buckets by the time they were last used, to find the least recently used one
+    ip_updates: BTreeSet<(Instant, IpAddr)>,
+}
+
+/// token buckets of all clients, kept across reloads of the API keys and relaunches
+#[derive(Clone, Default)]
+pub struct RateLimiter(Arc<Mutex<Buckets>>);
+
+impl RateLimiter {
+    /// takes `tokens` from the bucket of `id`, false if it has less and nothing is taken
+    fn take(&self, id: BucketId, limit: RateLimit, tokens: u32) -> bool {
+        let Some(rate) = limit.rate else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut guard = self.0.lock().unwrap();
+        let Buckets {
+            buckets,
+            ip_updates,
+        } = &mut *guard;
+        let ip = match id {
+            BucketId::Ip(ip) => Some(ip),
+            _ => None,
+        };
+        if ip.is_some()
+            && !buckets.contains_key(&id)
+            && ip_updates.len() >= MAX_IP_BUCKETS
+            && let Some((_, oldest)) = ip_updates.pop_first()
+        {
+            buckets.remove(&BucketId::Ip(oldest));
+        }
+        let bucket = buckets.entry(id).or_insert(Bucket {
+            tokens: limit.burst(),
+            updated: now,
+        });
+        if let Some(ip) = ip {
+            ip_updates.remove(&(bucket.updated, ip));
+            ip_updates.insert((now, ip));
+        }
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit.burst());
+        bucket.updated = now;
+        if bucket.tokens < tokens as f64 {
+            return false;
+        }
+        bucket.tokens -= tokens as f64;
+        true
+    }
+}
+
+/// name of the client of a query route for the metrics, set by the `Client` guard
+pub struct ClientName(pub Option<String>);
+
+/// request guard of the query routes, checks the API key and rate limit if --api-keys is given
+pub struct Client;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Client {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (name, result) = check(req);
+        req.local_cache(|| ClientName(Some(name)));
+        match result {
+            Ok(()) => Outcome::Success(Client),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
+
+/// request guard of /metrics, checks the same as `Client` but the request is not counted
+pub struct MetricsClient;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsClient {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match check(req).1 {
+            Ok(()) => Outcome::Success(MetricsClient),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
+
+/// name of the client for the metrics, and the status if the key is unknown or the rate limit is exceeded
+fn check(req: &Request<'_>) -> (String, Result<(), Status>) {
+    let lookup = req.rocket().state::<SharedLookup>().unwrap().get();
+    let Some(api_keys) = &lookup.api_keys else {
+        return (ANONYMOUS.into(), Ok(()));
+    };
+    let key = req
+        .headers()
+        .get_one("Authorization")
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .or_else(|| req.headers().get_one("X-Api-Key"));
+    let unauthorized = || (UNAUTHORIZED.into(), Err(Status::Unauthorized));
+    let (name, id, limit) = match (key, api_keys.anonymous, req.client_ip()) {
+        (Some(key), _, _) => match api_keys.keys.get(&hash_key(key.trim())) {
+            Some((name, limit)) => (name.as_str(), BucketId::Key(name.clone()), *limit),
+            None => return unauthorized(),
+        },
+        (None, Some(limit), ip) => (ANONYMOUS, ip.map_or(BucketId::Local, BucketId::Ip), limit),
+        (None, None, _) => return unauthorized(),
+    };
+    let limiter = req.rocket().state::<RateLimiter>().unwrap();
+    match limiter.take(id, limit, 1) {
+        true => (name.into(), Ok(())),
+        false => (name.into(), Err(Status::TooManyRequests)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lookup;
+    use crate::metrics::{CountRequests, Metrics};
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client as LocalClient;
+    use std::sync::RwLock;
+
+    const KEYS: &str = r#"
+        [anonymous]
+        rate = 0.001
+        burst = 2
+
+        [keys.billing]
+        key = "billing key"
+        rate = 0.001
+
+        [keys.gateway]
+        key = "gateway key"
+    "#;
+
+    async fn client(api_keys: &str) -> LocalClient {
+        let api_keys = ApiKeys::from_toml(api_keys).unwrap();
+        let lookup = SharedLookup(Arc::new(RwLock::new(Arc::new(Lookup::with_hashes(
+            &[],
+            Some(api_keys),
+        )))));
+        let figment = rocket::Config::figment().merge(("log_level", "off"));
+        let rocket = rocket::custom(figment)
+            .attach(CountRequests)
+            .manage(lookup)
+            .manage(RateLimiter::default())
+            .manage(Metrics::default())
+            .mount(
+                "/",
+                rocket::routes![crate::check_hash, crate::metrics::metrics],
+            );
+        LocalClient::untracked(rocket).await.unwrap()
+    }
+
+    async fn query(client: &LocalClient, key: Option<&str>, ip: [u8; 4]) -> Status {
+        let mut req = client
+            .post("/")
+            .body([0u8; 20])
+            .remote((IpAddr::from(ip), 1234).into());
+        if let Some(key) = key {
+            req = req.header(Header::new("X-Api-Key", key.to_string()));
+        }
+        req.dispatch().await.status()
+    }
+
+    #[test]
+    fn keys_file() {
+        let keys = ApiKeys::from_toml(KEYS).unwrap();
+        assert_eq!(keys.len(), 2);
+        let anonymous = keys.anonymous.unwrap();
+        assert_eq!((anonymous.rate, anonymous.burst()), (Some(0.001), 2.));
+        let (name, limit) = &keys.keys[&keys.find(b"billing key").unwrap()];
+        assert_eq!(
+            (name.as_str(), limit.rate, limit.burst()),
+            ("billing", Some(0.001), 1.)
+        );
+        let (name, limit) = &keys.keys[&keys.find(b"gateway key").unwrap()];
+        assert_eq!((name.as_str(), limit.rate), ("gateway", None));
+        assert_eq!(keys.find(b"unknown"), None);
+
+        let keys = ApiKeys::from_toml("[keys.a]\nkey = \"a\"").unwrap();
+        assert!(keys.anonymous.is_none());
+        assert!(ApiKeys::from_toml("[keys.a]\nkey = \"\"").is_err());
+        assert!(ApiKeys::from_toml("[keys.a]\nkey = \"a\"\n[keys.b]\nkey = \"a\"").is_err());
+        assert!(ApiKeys::from_toml("[keys.a]\nrate = 1").is_err());
+        assert!(ApiKeys::from_toml("[anonymous]\nrate = \"fast\"").is_err());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_unknown_key() {
+        let client = client(KEYS).await;
+        assert_eq!(
+            query(&client, Some("unknown"), [10, 0, 0, 1]).await,
+            Status::Unauthorized
+        );
+        let status = client
+            .post("/")
+            .body([0u8; 20])
+            .header(Header::new("Authorization", "Bearer gateway key"))
+            .dispatch()
+            .await
+            .status();
+        assert_eq!(status, Status::NoContent);
+
+        let client = self::client("[keys.gateway]\nkey = \"gateway key\"").await;
+        assert_eq!(
+            query(&client, None, [10, 0, 0, 1]).await,
+            Status::Unauthorized
+        );
+        assert_eq!(
+            query(&client, Some("gateway key"), [10, 0, 0, 1]).await,
+            Status::NoContent
+        );
+    }
+
+    #[rocket::async_test]
+    async fn limits_keys() {
+        let client = client(KEYS).await;
+        assert_eq!(
+            query(&client, Some("billing key"), [10, 0, 0, 1]).await,
+            Status::NoContent
+        );
+        assert_eq!(
+            query(&client, Some("billing key"), [10, 0, 0, 2]).await,
+            Status::TooManyRequests
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                query(&client, Some("gateway key"), [10, 0, 0, 1]).await,
+                Status::NoContent
+            );
+        }
+    }
+
+    #[rocket::async_test]
+    async fn limits_anonymous_per_ip() {
+        let client = client(KEYS).await;
+        for _ in 0..2 {
+            assert_eq!(query(&client, None, [10, 0, 0, 1]).await, Status::NoContent);
+        }
+        assert_eq!(
+            query(&client, None, [10, 0, 0, 1]).await,
+            Status::TooManyRequests
+        );
+        assert_eq!(query(&client, None, [10, 0, 0, 2]).await, Status::NoContent);
+        // keys don't use the bucket of the IP
+        assert_eq!(
+            query(&client, Some("billing key"), [10, 0, 0, 1]).await,
+            Status::NoContent
+        );
+    }
+
+    #[rocket::async_test]
+    async fn protects_metrics() {
+        let client = client("[keys.gateway]\nkey = \"gateway key\"").await;
+        assert_eq!(
+            query(&client, Some("unknown"), [10, 0, 0, 1]).await,
+            Status::Unauthorized
+        );
+        assert_eq!(
+            query(&client, Some("gateway key"), [10, 0, 0, 1]).await,
+            Status::NoContent
+        );
+        let status = client.get("/metrics").dispatch().await.status();
+        assert_eq!(status, Status::Unauthorized);
+        let response = client
+            .get("/metrics")
+            .header(Header::new("X-Api-Key", "gateway key"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let metrics = response.into_string().await.unwrap();
+        assert!(metrics.contains(
+            "ipwned_requests_total{client=\"unauthorized\",protocol=\"http\",code=\"401\"} 1\n"
+        ));
+        assert!(metrics.contains(
+            "ipwned_requests_total{client=\"gateway\",protocol=\"http\",code=\"204\"} 1\n"
+        ));
+        // requests on /metrics are not counted
+        assert_eq!(metrics.matches("ipwned_requests_total{").count(), 2);
+
+        // anonymous clients may read it like they may query
+        let client = self::client(KEYS).await;
+        assert_eq!(client.get("/metrics").dispatch().await.status(), Status::Ok);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_ip() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            rate: Some(0.001),
+            burst: Some(1.),
+        };
+        let ip =
+            |x: usize| BucketId::Ip(IpAddr::from([10, (x >> 16) as u8, (x >> 8) as u8, x as u8]));
+        assert!(limiter.take(ip(0), limit, 1));
+        for x in 1..MAX_IP_BUCKETS {
+            assert!(limiter.take(ip(x), limit, 1));
+        }
+        assert!(limiter.take(BucketId::Key("key".into()), limit, 1));
+        // IP 0 is used again, so IP 1 is the least recently used one
+        assert!(!limiter.take(ip(0), limit, 1));
+        assert!(limiter.take(ip(MAX_IP_BUCKETS), limit, 1));
+        let buckets = limiter.0.lock().unwrap();
+        assert_eq!(buckets.ip_updates.len(), MAX_IP_BUCKETS);
+        assert_eq!(buckets.buckets.len(), MAX_IP_BUCKETS + 1);
+        assert!(!buckets.buckets.contains_key(&ip(1)));
+        assert!(buckets.buckets.contains_key(&ip(0)));
+        assert!(buckets.buckets.contains_key(&BucketId::Key("key".into())));
+    }
+}
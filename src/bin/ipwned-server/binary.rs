@@ -0,0 +1,166 @@
+//! Server side of the binary protocol, see `ipwned_localdb::binary_protocol`. It runs next to Rocket on the same lookup.
+
+use ipwned_localdb::binary_protocol::{HASH_SIZE, MAX_HASHES, NOT_PWNED, PWNED};
+use std::io;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+
+use crate::SharedLookup;
+
+/// answers requests until the client closes the connection. answers are flushed once no further request is buffered,
+/// so pipelined requests share writes
+pub async fn serve_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    lookup: SharedLookup,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let result = answer_requests(&mut reader, &mut writer, lookup).await;
+    // the answers of the requests before an invalid one are still sent
+    result.and(writer.flush().await)
+}
+
+async fn answer_requests<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+    lookup: SharedLookup,
+) -> io::Result<()> {
+    let mut hashes = Vec::new();
+    let mut answers = Vec::new();
+    loop {
+        // closing the connection between requests is fine, within one it is an error
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(());
+        }
+        let len = reader.read_u32().await? as usize;
+        if !len.is_multiple_of(HASH_SIZE) || len > MAX_HASHES * HASH_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid request length {}", len),
+            ));
+        }
+        hashes.resize(len, 0);
+        reader.read_exact(&mut hashes).await?;
+        let lookup = lookup.get();
+        answers.clear();
+        answers.extend(hashes.chunks_exact(HASH_SIZE).map(|x| {
+            match lookup.contains(x.try_into().unwrap()) {
+                true => PWNED,
+                false => NOT_PWNED,
+            }
+        }));
+        writer.write_all(&answers).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+
+    const PWNED_HASH: [u8; 20] = [1; 20];
+    const ALLOWED_HASH: [u8; 20] = [2; 20];
+    const CLEAN_HASH: [u8; 20] = [3; 20];
+
+    fn lookup() -> SharedLookup {
+        let lookup = crate::Lookup::with_hashes(&[PWNED_HASH, ALLOWED_HASH], None)
+            .allowlisting("binary", &[ALLOWED_HASH]);
+        SharedLookup(Arc::new(RwLock::new(Arc::new(lookup))))
+    }
+
+    fn request(hashes: &[[u8; 20]]) -> Vec<u8> {
+        let mut request = ((hashes.len() * HASH_SIZE) as u32).to_be_bytes().to_vec();
+        request.extend(hashes.iter().flatten());
+        request
+    }
+
+    /// writes `request` while reading the answers, the result of the server and all answers
+    async fn exchange(request: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(serve_connection(server, lookup()));
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let writer = tokio::spawn(async move {
+            writer.write_all(&request).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        let mut answers = Vec::new();
+        reader.read_to_end(&mut answers).await.unwrap();
+        writer.await.unwrap();
+        (server.await.unwrap(), answers)
+    }
+
+    #[tokio::test]
+    async fn answers() {
+        let (result, answers) =
+            exchange(request(&[PWNED_HASH, CLEAN_HASH, ALLOWED_HASH, PWNED_HASH])).await;
+        result.unwrap();
+        assert_eq!(answers, [PWNED, NOT_PWNED, NOT_PWNED, PWNED]);
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        // more requests than the reference client keeps unanswered, each larger than the buffers of the connection
+        let mut requests = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..16 {
+            let hashes: Vec<_> = (0..1000 + i)
+                .map(|x| {
+                    if x % 3 == i % 3 {
+                        PWNED_HASH
+                    } else {
+                        CLEAN_HASH
+                    }
+                })
+                .collect();
+            requests.extend(request(&hashes));
+            expected.extend(hashes.iter().map(|x| match x == &PWNED_HASH {
+                true => PWNED,
+                false => NOT_PWNED,
+            }));
+        }
+        requests.extend(request(&[]));
+        requests.extend(request(&[ALLOWED_HASH; MAX_HASHES]));
+        expected.extend([NOT_PWNED; MAX_HASHES]);
+        let (result, answers) = exchange(requests).await;
+        result.unwrap();
+        assert!(answers == expected);
+    }
+
+    #[tokio::test]
+    async fn invalid_length() {
+        for len in [
+            1,
+            HASH_SIZE + 1,
+            (MAX_HASHES + 1) * HASH_SIZE,
+            u32::MAX as usize,
+        ] {
+            let mut requests = request(&[PWNED_HASH]);
+            requests.extend((len as u32).to_be_bytes());
+            requests.extend([0; 40]);
+            requests.extend(request(&[PWNED_HASH]));
+            let (result, answers) = exchange(requests).await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(answers, [PWNED]);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_request() {
+        let mut requests = request(&[PWNED_HASH]);
+        requests.extend(request(&[PWNED_HASH, CLEAN_HASH]));
+        requests.truncate(requests.len() - 1);
+        let (result, answers) = exchange(requests).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(answers, [PWNED]);
+
+        // a connection closed within the length is an error as well, only one closed between requests is not
+        let (result, answers) = exchange(vec![0, 0]).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(answers.is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+//! DNSBL style responder for appliances that can only make DNS queries. A queries for `<hex-sha1>.<zone>` are answered
+//! with 127.0.0.2 if the hash is pwned and NXDOMAIN otherwise, the 40 hex characters may be split across labels.
+//!
+//! only the parts of DNS needed for this are implemented: one question per query, no EDNS, and a synthetic SOA record
+//! of the zone in negative answers so resolvers cache them.
+
+use log::{error, warn};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::UdpSocket;
+
+use crate::{Lookup, SharedLookup};
+
+/// TTL of all records, also the negative caching TTL in the SOA record
+const TTL: u32 = 300;
+/// answer of pwned hashes, 127.0.0.2 is the usual "listed" answer of DNSBLs
+const PWNED_ADDRESS: [u8; 4] = [127, 0, 0, 2];
+/// UDP queries are at most 512 bytes without EDNS, larger buffers of EDNS clients are accepted
+const MAX_UDP_QUERY: usize = 4096;
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const NOERROR: u8 = 0;
+const FORMERR: u8 = 1;
+const NXDOMAIN: u8 = 3;
+const NOTIMP: u8 = 4;
+const REFUSED: u8 = 5;
+
+/// domain the responder is authoritative for, as lowercase labels
+pub struct Zone(Vec<Vec<u8>>);
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let labels: Vec<Vec<u8>> = s
+            .trim_end_matches('.')
+            .split('.')
+            .map(|x| x.to_ascii_lowercase().into_bytes())
+            .collect();
+        let valid_label = |x: &Vec<u8>| {
+            (1..=63).contains(&x.len()) && x.iter().all(|x| x.is_ascii_alphanumeric() || *x == b'-')
+        };
+        // the name of a query is at most 255 bytes, 41 of them for the hash
+        let len: usize = labels.iter().map(|x| x.len() + 1).sum();
+        if !labels.iter().all(valid_label) || len > 255 - 41 - 1 {
+            return Err(format!("invalid DNS zone {}", s));
+        }
+        Ok(Zone(labels))
+    }
+}
+
+struct Question<'a> {
+    labels: Vec<&'a [u8]>,
+    qtype: u16,
+    qclass: u16,
+    /// offset of the end of the question in the query
+    end: usize,
+}
+
+/// parses the first question, names in queries are not compressed
+fn parse_question(query: &[u8]) -> Option<Question<'_>> {
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(query.get(pos..pos + len)?);
+        pos += len;
+    }
+    if pos - HEADER_LEN > 255 {
+        return None;
+    }
+    let fields = query.get(pos..pos + 4)?;
+    Some(Question {
+        labels,
+        qtype: u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+        end: pos + 4,
+    })
+}
+
+/// the response to a query, `None` if it is too short to answer or is a response itself
+pub fn answer(query: &[u8], zone: &Zone, lookup: &Lookup) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+        return None;
+    }
+    let opcode = (query[2] >> 3) & 0x0f;
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    if opcode != 0 {
+        return Some(header(query, NOTIMP, false));
+    }
+    let Some(question) = parse_question(query).filter(|_| qdcount == 1) else {
+        return Some(header(query, FORMERR, false));
+    };
+    let mut response = header(query, NOERROR, true);
+    response.extend(&query[HEADER_LEN..question.end]);
+    let in_zone = question.labels.len() >= zone.0.len()
+        && question.labels[question.labels.len() - zone.0.len()..]
+            .iter()
+            .zip(&zone.0)
+            .all(|(x, y)| x.eq_ignore_ascii_case(y));
+    if !in_zone || !matches!(question.qclass, CLASS_IN | CLASS_ANY) {
+        response[3] = REFUSED;
+        return Some(response);
+    }
+    let hash_labels = &question.labels[..question.labels.len() - zone.0.len()];
+    let zone_offset = HEADER_LEN + hash_labels.iter().map(|x| x.len() + 1).sum::<usize>();
+    let (rcode, record) = match hash_labels {
+        [] => (
+            NOERROR,
+            matches!(question.qtype, TYPE_SOA | TYPE_ANY).then_some(TYPE_SOA),
+        ),
+        _ if is_pwned(hash_labels, lookup) => (
+            NOERROR,
+            matches!(question.qtype, TYPE_A | TYPE_ANY).then_some(TYPE_A),
+        ),
+        _ => (NXDOMAIN, None),
+    };
+    response[3] = rcode;
+    match record {
+        Some(TYPE_A) => {
+            // the name is a pointer to the one of the question
+            response.extend(&(0xc000 | HEADER_LEN as u16).to_be_bytes());
+            write_record_fields(&mut response, TYPE_A, PWNED_ADDRESS.len());
+            response.extend(&PWNED_ADDRESS);
+            response[7] = 1;
+        }
+        Some(_) => {
+            write_soa(&mut response, zone_offset);
+            response[7] = 1;
+        }
+        None => {
+            write_soa(&mut response, zone_offset);
+            response[9] = 1;
+        }
+    }
+    Some(response)
+}
+
+fn is_pwned(labels: &[&[u8]], lookup: &Lookup) -> bool {
+    let hex = labels.concat();
+    let mut hash = [0u8; 20];
+    hex.len() == 40 && faster_hex::hex_decode(&hex, &mut hash).is_ok() && lookup.contains(&hash)
+}
+
+/// header of a response to `query` with one question if `question` is set and no records yet
+fn header(query: &[u8], rcode: u8, question: bool) -> Vec<u8> {
+    let mut header = Vec::with_capacity(128);
+    header.extend(&query[..2]);
+    // response, opcode and recursion desired of the query, authoritative answer
+    header.push(0x80 | (query[2] & 0x79) | 0x04);
+    header.push(rcode);
+    header.extend(&(question as u16).to_be_bytes());
+    header.extend(&[0; 6]);
+    header
+}
+
+fn write_record_fields(response: &mut Vec<u8>, rtype: u16, rdata_len: usize) {
+    response.extend(&rtype.to_be_bytes());
+    response.extend(&CLASS_IN.to_be_bytes());
+    response.extend(&TTL.to_be_bytes());
+    response.extend(&(rdata_len as u16).to_be_bytes());
+}
+
+/// SOA record of the zone, whose name starts at `zone_offset` in the question
+fn write_soa(response: &mut Vec<u8>, zone_offset: usize) {
+    let zone = (0xc000 | zone_offset as u16).to_be_bytes();
+    let mut rdata = Vec::with_capacity(40);
+    // primary name server is the zone itself, the responsible mailbox hostmaster@zone
+    rdata.extend(&zone);
+    rdata.push(10);
+    rdata.extend(b"hostmaster");
+    rdata.extend(&zone);
+    // serial, refresh, retry, expire and the negative caching TTL
+    for value in [1, 3600, 600, 86400, TTL] {
+        rdata.extend(&value.to_be_bytes());
+    }
+    response.extend(&zone);
+    write_record_fields(response, TYPE_SOA, rdata.len());
+    response.extend(&rdata);
+}
+
+pub async fn serve_udp(socket: UdpSocket, zone: Arc<Zone>, lookup: SharedLookup) {
+    let mut buffer = vec![0u8; MAX_UDP_QUERY];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("unable to receive DNS query: {:?}", e);
+                continue;
+            }
+        };
+        let Some(response) = answer(&buffer[..len], &zone, &lookup.get()) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response, addr).await {
+            warn!("unable to send DNS response to {}: {}", addr, e);
+        }
+    }
+}
+
+/// answers queries with a 2 byte length prefix until the client closes the connection
+pub async fn serve_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    zone: Arc<Zone>,
+    lookup: SharedLookup,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut query = Vec::new();
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(x) => x as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        query.resize(len, 0);
+        reader.read_exact(&mut query).await?;
+        let Some(response) = answer(&query, &zone, &lookup.get()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid DNS query",
+            ));
+        };
+        writer.write_u16(response.len() as u16).await?;
+        writer.write_all(&response).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PWNED: &str = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3";
+    const NOT_PWNED: &str = "0000000000000000000000000000000000000000";
+    const TYPE_AAAA: u16 = 28;
+
+    fn lookup() -> Lookup {
+        let mut hash = [0u8; 20];
+        faster_hex::hex_decode(PWNED.as_bytes(), &mut hash).unwrap();
+        Lookup::with_hashes(&[hash], None)
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        // id 0x1234, recursion desired, one question
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.').filter(|x| !x.is_empty()) {
+            query.push(label.len() as u8);
+            query.extend(label.as_bytes());
+        }
+        query.push(0);
+        query.extend(&qtype.to_be_bytes());
+        query.extend(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn ask(name: &str, qtype: u16) -> Vec<u8> {
+        let zone = Zone::from_str("pwned.example.com").unwrap();
+        answer(&query(name, qtype), &zone, &lookup()).unwrap()
+    }
+
+    fn read_u16(data: &[u8], pos: usize) -> u16 {
+        u16::from_be_bytes([data[pos], data[pos + 1]])
+    }
+
+    /// the name at `pos` with compression pointers followed, and the position after it
+    fn read_name(data: &[u8], mut pos: usize) -> (String, usize) {
+        let mut labels = Vec::new();
+        let mut end = None;
+        loop {
+            let len = data[pos] as usize;
+            if len & 0xc0 == 0xc0 {
+                end.get_or_insert(pos + 2);
+                pos = read_u16(data, pos) as usize & 0x3fff;
+                continue;
+            }
+            if len == 0 {
+                return (labels.join("."), end.unwrap_or(pos + 1));
+            }
+            labels.push(String::from_utf8(data[pos + 1..pos + 1 + len].to_vec()).unwrap());
+            pos += 1 + len;
+        }
+    }
+
+    /// rcode and the answer and authority counts of a response
+    fn summary(response: &[u8]) -> (u8, u16, u16) {
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(
+            response[2], 0x85,
+            "response, authoritative, recursion desired"
+        );
+        (response[3], read_u16(response, 6), read_u16(response, 8))
+    }
+
+    /// the name, type and rdata of the first record after the question
+    fn first_record(response: &[u8]) -> (String, u16, &[u8]) {
+        let (_, question_end) = read_name(response, HEADER_LEN);
+        let (name, pos) = read_name(response, question_end + 4);
+        assert_eq!(read_u16(response, pos + 2), CLASS_IN);
+        let len = read_u16(response, pos + 8) as usize;
+        assert_eq!(response.len(), pos + 10 + len);
+        (name, read_u16(response, pos), &response[pos + 10..])
+    }
+
+    fn assert_soa(response: &[u8]) {
+        let (name, rtype, rdata) = first_record(response);
+        assert_eq!((name.as_str(), rtype), ("pwned.example.com", TYPE_SOA));
+        let (mname, pos) = read_name(response, response.len() - rdata.len());
+        let (rname, pos) = read_name(response, pos);
+        assert_eq!(mname, "pwned.example.com");
+        assert_eq!(rname, "hostmaster.pwned.example.com");
+        assert_eq!(response.len() - pos, 20);
+    }
+
+    #[test]
+    fn pwned_hash() {
+        let response = ask(&format!("{}.pwned.example.com", PWNED), TYPE_A);
+        assert_eq!(summary(&response), (NOERROR, 1, 0));
+        let (name, rtype, rdata) = first_record(&response);
+        assert_eq!(name, format!("{}.pwned.example.com", PWNED));
+        assert_eq!(rtype, TYPE_A);
+        assert_eq!(rdata, PWNED_ADDRESS);
+    }
+
+    #[test]
+    fn hash_split_across_labels() {
+        let name = format!("{}.{}.PWNED.example.com", &PWNED[..20], &PWNED[20..]);
+        let response = ask(&name, TYPE_A);
+        assert_eq!(summary(&response), (NOERROR, 1, 0));
+        assert_eq!(first_record(&response).2, PWNED_ADDRESS);
+    }
+
+    #[test]
+    fn not_pwned_hash() {
+        for name in [NOT_PWNED, "invalid", &PWNED[1..]] {
+            let response = ask(&format!("{}.pwned.example.com", name), TYPE_A);
+            assert_eq!(summary(&response), (NXDOMAIN, 0, 1));
+            assert_soa(&response);
+        }
+    }
+
+    #[test]
+    fn no_data() {
+        let response = ask(&format!("{}.pwned.example.com", PWNED), TYPE_AAAA);
+        assert_eq!(summary(&response), (NOERROR, 0, 1));
+        assert_soa(&response);
+        let response = ask("pwned.example.com", TYPE_A);
+        assert_eq!(summary(&response), (NOERROR, 0, 1));
+        assert_soa(&response);
+        let response = ask("pwned.example.com", TYPE_SOA);
+        assert_eq!(summary(&response), (NOERROR, 1, 0));
+        assert_soa(&response);
+    }
+
+    #[test]
+    fn out_of_zone() {
+        for name in [
+            format!("{}.example.com", PWNED),
+            format!("{}.pwned.example.org", PWNED),
+            String::from("com"),
+        ] {
+            let response = ask(&name, TYPE_A);
+            assert_eq!(summary(&response), (REFUSED, 0, 0));
+            assert_eq!(read_u16(&response, 4), 1);
+        }
+    }
+
+    #[test]
+    fn malformed_query() {
+        let zone = Zone::from_str("pwned.example.com").unwrap();
+        let lookup = lookup();
+        let formerr = |query: &[u8]| {
+            let response = answer(query, &zone, &lookup).unwrap();
+            assert_eq!(summary(&response), (FORMERR, 0, 0));
+            assert_eq!(read_u16(&response, 4), 0);
+        };
+        let mut query = query("pwned.example.com", TYPE_A);
+        for qdcount in [0, 2] {
+            query[5] = qdcount;
+            formerr(&query);
+        }
+        query[5] = 1;
+        formerr(&query[..query.len() - 1]);
+        formerr(&query[..HEADER_LEN + 3]);
+        formerr(&query[..HEADER_LEN]);
+        assert!(answer(&query[..HEADER_LEN - 1], &zone, &lookup).is_none());
+        query[2] |= 0x80;
+        assert!(answer(&query, &zone, &lookup).is_none());
+    }
+}
@@ -0,0 +1,190 @@
+//! gRPC service of proto/ipwned.proto, served by tonic next to Rocket on the same lookup.
+
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{SharedLookup, SourceInfo};
+
+mod proto {
+    tonic::include_proto!("ipwned.v1");
+}
+
+use proto::lookup_server::{Lookup, LookupServer};
+use proto::{
+    CheckBatchRequest, CheckBatchResponse, CheckRequest, CheckResponse, FilterInfo, InfoRequest,
+    InfoResponse,
+};
+
+struct LookupService(SharedLookup);
+
+fn parse_hash(hash: &[u8]) -> Result<&[u8; 20], Status> {
+    hash.try_into()
+        .map_err(|_| Status::invalid_argument("SHA1 hash must be 20 bytes long"))
+}
+
+impl From<crate::FilterInfo> for FilterInfo {
+    fn from(x: crate::FilterInfo) -> Self {
+        FilterInfo {
+            entries: x.entries,
+            capacity: x.capacity,
+            max_error_rate: x.max_error_rate,
+            current_error_rate: x.current_error_rate,
+            memory_usage: x.memory_usage as u64,
+        }
+    }
+}
+
+impl From<SourceInfo> for proto::SourceInfo {
+    fn from(x: SourceInfo) -> Self {
+        proto::SourceInfo {
+            path: x.path,
+            kind: x.kind,
+            hashes: x.hashes,
+            last_update: x.last_update,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Lookup for LookupService {
+    async fn check(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<CheckResponse>, Status> {
+        let pwned = self.0.get().contains(parse_hash(&request.get_ref().sha1)?);
+        Ok(Response::new(CheckResponse { pwned }))
+    }
+
+    type CheckBatchStream = Pin<Box<dyn Stream<Item = Result<CheckBatchResponse, Status>> + Send>>;
+
+    async fn check_batch(
+        &self,
+        request: Request<Streaming<CheckBatchRequest>>,
+    ) -> Result<Response<Self::CheckBatchStream>, Status> {
+        Ok(Response::new(check_batches(
+            self.0.clone(),
+            request.into_inner(),
+        )))
+    }
+
+    async fn info(&self, _request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
+        let crate::Info {
+            filter,
+            ntlm_filter,
+            sources,
+        } = crate::Info::new(&self.0.get());
+        Ok(Response::new(InfoResponse {
+            filter: Some(filter.into()),
+            ntlm_filter: ntlm_filter.map(Into::into),
+            sources: sources.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+/// one response per request of the stream, in order. takes any stream, tonic's `Streaming` only decodes HTTP bodies
+fn check_batches(
+    lookup: SharedLookup,
+    requests: impl Stream<Item = Result<CheckBatchRequest, Status>> + Send + 'static,
+) -> <LookupService as Lookup>::CheckBatchStream {
+    let responses = requests.map(move |request| {
+        let lookup = lookup.get();
+        let pwned = request?
+            .sha1
+            .iter()
+            .map(|x| Ok(lookup.contains(parse_hash(x)?)))
+            .collect::<Result<_, Status>>()?;
+        Ok(CheckBatchResponse { pwned })
+    });
+    Box::pin(responses)
+}
+
+pub async fn serve(listener: TcpListener, lookup: SharedLookup) {
+    let result = Server::builder()
+        .add_service(LookupServer::new(LookupService(lookup)))
+        .serve_with_incoming(TcpIncoming::from(listener).with_nodelay(Some(true)))
+        .await;
+    if let Err(e) = result {
+        log::error!("gRPC server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use tonic::Code;
+
+    const PWNED: [u8; 20] = [1; 20];
+    const ALLOWED: [u8; 20] = [2; 20];
+    const CLEAN: [u8; 20] = [3; 20];
+
+    fn service() -> LookupService {
+        let lookup =
+            crate::Lookup::with_hashes(&[PWNED, ALLOWED], None).allowlisting("grpc", &[ALLOWED]);
+        LookupService(SharedLookup(Arc::new(RwLock::new(Arc::new(lookup)))))
+    }
+
+    async fn check(service: &LookupService, sha1: &[u8]) -> Result<bool, Status> {
+        let request = Request::new(CheckRequest {
+            sha1: sha1.to_vec(),
+        });
+        Ok(service.check(request).await?.into_inner().pwned)
+    }
+
+    #[tokio::test]
+    async fn check_hash() {
+        let service = service();
+        assert!(check(&service, &PWNED).await.unwrap());
+        assert!(!check(&service, &CLEAN).await.unwrap());
+        assert!(!check(&service, &ALLOWED).await.unwrap());
+        for sha1 in [&[][..], &PWNED[..19], &[1; 21]] {
+            let status = check(&service, sha1).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_batch() {
+        let service = service();
+        let batch = |hashes: &[&[u8]]| {
+            Ok(CheckBatchRequest {
+                sha1: hashes.iter().map(|x| x.to_vec()).collect(),
+            })
+        };
+        let requests = futures::stream::iter([
+            batch(&[&CLEAN, &PWNED, &ALLOWED, &PWNED]),
+            batch(&[]),
+            batch(&[&PWNED]),
+            batch(&[&CLEAN, &PWNED[..10]]),
+        ]);
+        let responses: Vec<_> = check_batches(service.0.clone(), requests).collect().await;
+        let pwned: Vec<_> = responses[..3]
+            .iter()
+            .map(|x| x.as_ref().unwrap().pwned.clone())
+            .collect();
+        assert_eq!(pwned, [vec![false, true, false, true], vec![], vec![true]]);
+        assert_eq!(
+            responses[3].as_ref().unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn info() {
+        let service = service();
+        let response = service
+            .info(Request::new(InfoRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let expected: FilterInfo = service.0.get().filter.info().into();
+        assert_eq!(response.filter, Some(expected));
+        assert_eq!(response.filter.unwrap().entries, 2);
+        assert!(response.ntlm_filter.is_none());
+        assert!(response.sources.is_empty());
+    }
+}
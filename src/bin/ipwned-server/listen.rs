@@ -0,0 +1,62 @@
+//! Accept loops of the protocols served next to Rocket, each connection is served in its own task.
+
+use log::{error, info, warn};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+pub async fn bind_tcp(addr: SocketAddr, protocol: &str) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("{} listening on {}", protocol, addr);
+    Ok(listener)
+}
+
+pub async fn serve_tcp<F, Fut>(listener: TcpListener, protocol: &'static str, serve: F)
+where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("unable to accept {} connection: {:?}", protocol, e);
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let connection = serve(stream);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("closed {} connection of {}: {}", protocol, addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+pub async fn serve_unix<F, Fut>(
+    listener: tokio::net::UnixListener,
+    protocol: &'static str,
+    serve: F,
+) where
+    F: Fn(tokio::net::UnixStream) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("unable to accept {} connection: {:?}", protocol, e);
+                continue;
+            }
+        };
+        let connection = serve(stream);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("closed {} connection: {}", protocol, e);
+            }
+        });
+    }
+}
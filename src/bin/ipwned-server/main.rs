@@ -0,0 +1,804 @@
+mod auth;
+mod binary;
+mod dns;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod listen;
+mod metrics;
+mod resp;
+mod tls;
+#[cfg(unix)]
+mod unix;
+
+use argh::FromArgs;
+use auth::{ApiKeys, Client, RateLimiter};
+use ipwned_localdb::allowlist::Allowlist;
+use ipwned_localdb::logging::{JsonLogger, LogFormat};
+use ipwned_localdb::pwned_filter::ntlm;
+use ipwned_localdb::statedb::StateDatabase;
+use ipwned_localdb::{FilterInfo, PwnedFilter};
+use log::{LevelFilter, error, info, warn};
+use metrics::{CountRequests, Metrics};
+use rocket::config::TlsConfig;
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::shield::Shield;
+use rocket::tokio::io::AsyncReadExt;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, RwLock};
+use tls::{Relaunch, TlsFiles};
+use zeroize::Zeroizing;
+
+/// default for the `password` limit in Rocket.toml, the maximum length of a password on /password
+const DEFAULT_PASSWORD_LIMIT: ByteUnit = ByteUnit::Byte(1024);
+/// upper bound of the `password` limit, a buffer of the limit's size is allocated for each request to /password
+const MAX_PASSWORD_LIMIT: ByteUnit = ByteUnit::Kibibyte(4);
+
+#[derive(FromArgs, Clone)]
+/// run an HTTP server for querying a local haveibeenpwned.com password lookup table
+struct CliArgs {
+    /// file name of the lookup filter file. default: ./ipwned_qfilter.cbor
+    #[argh(option, short = 'f', default = "String::from(\"ipwned_qfilter.cbor\")")]
+    filter_path: String,
+
+    /// state database of the builder, used to report included extra sources on /info. default: none
+    #[argh(option, short = 's')]
+    state_db_path: Option<String>,
+
+    /// allowlist of hashes that are never reported as pwned, see Readme for the file format. default: none
+    #[argh(option, short = 'a')]
+    allowlist: Option<String>,
+
+    /// NTLM lookup filter file, built with ipwned-builder --hash-type ntlm. also checks passwords on /password against
+    /// it. default: none
+    #[argh(option)]
+    ntlm_filter_path: Option<String>,
+
+    /// TOML file with API keys and rate limits for the query routes, see Readme for the format. default: none, no
+    /// authentication
+    #[argh(option)]
+    api_keys: Option<String>,
+
+    /// accept plaintext passwords on /password. requires TLS or --no-tcp with --unix-socket, requests to it are not
+    /// logged. default: off
+    #[argh(switch)]
+    password_endpoint: bool,
+
+    /// PEM file with the TLS certificate chain, enables TLS together with --tls-key. overrides tls.certs of
+    /// Rocket.toml. default: none
+    #[argh(option)]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key of the TLS certificate. overrides tls.key of Rocket.toml. default: none
+    #[argh(option)]
+    tls_key: Option<String>,
+
+    /// PEM file with the CA certificates client certificates are verified against, enables mTLS. overrides
+    /// tls.mutual.ca_certs of Rocket.toml. default: none
+    #[argh(option)]
+    tls_client_ca: Option<String>,
+
+    /// reject clients without a valid certificate, requires --tls-client-ca. overrides tls.mutual.mandatory of
+    /// Rocket.toml
+    #[argh(switch)]
+    tls_client_cert_required: bool,
+
+    /// also serve HTTP on this unix domain socket, an existing socket file is replaced. default: none
+    #[argh(option)]
+    unix_socket: Option<PathBuf>,
+
+    /// permissions of the unix sockets as octal number. default: 660
+    #[argh(option, default = "String::from(\"660\")")]
+    unix_socket_mode: String,
+
+    /// owner of the unix sockets as user, user:group or :group, by name or id. default: the user running the server
+    #[argh(option)]
+    unix_socket_owner: Option<String>,
+
+    /// serve the binary protocol for bulk lookups on this TCP address, see Readme. default: none
+    #[argh(option)]
+    binary_listen: Option<SocketAddr>,
+
+    /// serve the binary protocol on this unix domain socket. default: none
+    #[argh(option)]
+    binary_unix_socket: Option<PathBuf>,
+
+    /// serve a Redis compatible protocol on this TCP address, see Readme. with --api-keys clients authenticate with
+    /// AUTH. default: none
+    #[argh(option)]
+    resp_listen: Option<SocketAddr>,
+
+    /// serve the Redis compatible protocol on this unix domain socket. default: none
+    #[argh(option)]
+    resp_unix_socket: Option<PathBuf>,
+
+    /// answer DNS queries for <hex-sha1>.<dns-zone> over UDP and TCP on this address, see Readme. default: none
+    #[argh(option)]
+    dns_listen: Option<SocketAddr>,
+
+    /// zone of the DNS responder. default: pwned.example.internal
+    #[argh(option, default = "String::from(\"pwned.example.internal\")")]
+    dns_zone: String,
+
+    /// serve the gRPC service of proto/ipwned.proto on this TCP address, requires the grpc feature. default: none
+    #[argh(option)]
+    grpc_listen: Option<SocketAddr>,
+
+    /// only serve on the unix socket, binary protocol, Redis protocol, DNS and gRPC listeners, without listening on the
+    /// TCP address of Rocket.toml
+    #[argh(switch)]
+    no_tcp: bool,
+
+    /// start listeners that don't support the configured --api-keys or TLS anyway, see Readme
+    #[argh(switch)]
+    allow_unprotected_listeners: bool,
+
+    /// write the process id to this file, e.g. for ipwned-builder daemon --notify-pid-file. default: none
+    #[argh(option)]
+    pid_file: Option<String>,
+
+    /// log format. allowed options: text json. text uses Rocket's logger, json logs at info level. default: text
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+}
+
+#[derive(Clone, Serialize)]
+struct SourceInfo {
+    path: String,
+    kind: String,
+    hashes: Option<u32>,
+    last_update: String,
+}
+
+#[derive(Serialize)]
+struct Info {
+    filter: FilterInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ntlm_filter: Option<FilterInfo>,
+    sources: Vec<SourceInfo>,
+}
+
+impl Info {
+    fn new(lookup: &Lookup) -> Info {
+        Info {
+            filter: lookup.filter.info(),
+            ntlm_filter: lookup.ntlm_filter.as_ref().map(PwnedFilter::info),
+            sources: lookup.sources.clone(),
+        }
+    }
+}
+
+/// everything the server loads from disk, replaced as a whole when reloading
+struct Lookup {
+    filter: PwnedFilter,
+    ntlm_filter: Option<PwnedFilter>,
+    allowlist: Allowlist,
+    api_keys: Option<ApiKeys>,
+    sources: Vec<SourceInfo>,
+}
+
+impl Lookup {
+    fn contains(&self, hash: &[u8; 20]) -> bool {
+        !self.allowlist.contains(hash) && self.filter.contains(hash)
+    }
+
+    /// checks the SHA1 hash and, if an NTLM filter is loaded, the NTLM hash. the allowlist covers both
+    fn contains_password(&self, password: &str) -> bool {
+        let sha1: Zeroizing<[u8; 20]> = Zeroizing::new(Sha1::digest(password.as_bytes()).into());
+        if self.allowlist.contains(&*sha1) {
+            return false;
+        }
+        if self.filter.contains(&sha1) {
+            return true;
+        }
+        match &self.ntlm_filter {
+            Some(filter) => filter.contains_ntlm(&Zeroizing::new(ntlm(password))),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Lookup {
+    /// lookup of a filter with the given SHA1 hashes, for the tests of the listeners
+    fn with_hashes(hashes: &[[u8; 20]], api_keys: Option<ApiKeys>) -> Lookup {
+        let mut filter = qfilter::Filter::new(1024, 1e-6).unwrap();
+        for hash in hashes {
+            filter.insert(&hash[..]).unwrap();
+        }
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&filter, &mut cbor).unwrap();
+        Lookup {
+            filter: PwnedFilter::from_reader(&cbor[..]).unwrap(),
+            ntlm_filter: None,
+            allowlist: Allowlist::default(),
+            api_keys,
+            sources: Vec::new(),
+        }
+    }
+
+    /// allowlists the hashes without an expiry, through a temporary allowlist file named `name`
+    fn allowlisting(mut self, name: &str, hashes: &[[u8; 20]]) -> Lookup {
+        let path = std::env::temp_dir().join(format!(
+            "ipwned_{}_allowlist_test_{}",
+            name,
+            std::process::id()
+        ));
+        let entries: String = hashes
+            .iter()
+            .map(|x| format!("{} 9999-12-31 test\n", faster_hex::hex_string(x)))
+            .collect();
+        std::fs::write(&path, entries).unwrap();
+        self.allowlist = Allowlist::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        self
+    }
+}
+
+#[derive(Clone)]
+struct SharedLookup(Arc<RwLock<Arc<Lookup>>>);
+
+impl SharedLookup {
+    fn get(&self) -> Arc<Lookup> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, lookup: Lookup) {
+        *self.0.write().unwrap() = Arc::new(lookup);
+    }
+}
+
+#[rocket::post("/", data = "<hash>")]
+fn check_hash(_client: Client, hash: &[u8], lookup: &rocket::State<SharedLookup>) -> Status {
+    let status = match <&[u8; 20]>::try_from(hash) {
+        Err(_) => 400,
+        Ok(hash) if lookup.get().contains(hash) => 205,
+        Ok(_) => 204,
+    };
+    Status { code: status }
+}
+
+/// the password is read into a buffer of the maximum size, so it is never copied by growing the buffer, and the
+/// buffer is zeroed when dropped
+#[rocket::post("/password", data = "<password>")]
+async fn check_password(
+    _client: Client,
+    password: Data<'_>,
+    limits: &Limits,
+    lookup: &rocket::State<SharedLookup>,
+) -> Status {
+    let limit = limits.get("password").unwrap_or(DEFAULT_PASSWORD_LIMIT);
+    let mut buffer = Zeroizing::new(vec![0u8; limit.as_u64() as usize + 1]);
+    let mut stream = password.open(limit + 1);
+    let mut len = 0;
+    while len < buffer.len() {
+        match stream.read(&mut buffer[len..]).await {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(_) => return Status::BadRequest,
+        }
+    }
+    if len > limit.as_u64() as usize {
+        return Status::PayloadTooLarge;
+    }
+    let status = match std::str::from_utf8(&buffer[..len]) {
+        Err(_) => 400,
+        Ok(password) if lookup.get().contains_password(password) => 205,
+        Ok(_) => 204,
+    };
+    Status { code: status }
+}
+
+#[rocket::get("/info")]
+fn info(lookup: &rocket::State<SharedLookup>) -> Json<Info> {
+    Json(Info::new(&lookup.get()))
+}
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    let args: CliArgs = argh::from_env();
+    let unix_sockets = [
+        &args.unix_socket,
+        &args.binary_unix_socket,
+        &args.resp_unix_socket,
+    ];
+    if args.no_tcp
+        && unix_sockets.iter().all(|x| x.is_none())
+        && args.binary_listen.is_none()
+        && args.resp_listen.is_none()
+        && args.dns_listen.is_none()
+        && args.grpc_listen.is_none()
+    {
+        eprintln!(
+            "--no-tcp requires --unix-socket or a binary, Redis protocol, DNS or gRPC listener"
+        );
+        return ExitCode::FAILURE;
+    }
+    #[cfg(not(feature = "grpc"))]
+    if args.grpc_listen.is_some() {
+        eprintln!("--grpc-listen requires building with the grpc feature");
+        return ExitCode::FAILURE;
+    }
+    #[cfg(not(unix))]
+    if unix_sockets.iter().any(|x| x.is_some()) {
+        eprintln!("unix sockets are only supported on unix");
+        return ExitCode::FAILURE;
+    }
+    let mut figment = merge_tls_args(rocket::Config::figment(), &args);
+    if args.log_format == LogFormat::Json {
+        let mut logger = JsonLogger::new(LevelFilter::Info);
+        if args.password_endpoint {
+            // request logging of Rocket, errors and warnings don't contain request bodies
+            logger = logger.quiet_target("rocket::server");
+        }
+        // Rocket only installs its own logger if none is set yet
+        log::set_boxed_logger(logger).unwrap();
+        log::set_max_level(LevelFilter::Info);
+    } else if args.no_tcp && args.unix_socket.is_none() {
+        // Rocket is never launched, so its logger isn't installed either
+        simplelog::SimpleLogger::init(LevelFilter::Info, simplelog::Config::default()).unwrap();
+    } else if args.password_endpoint {
+        // Rocket's logger can't filter by target, critical drops request logging along with other info messages
+        figment = figment.merge(("log_level", rocket::config::LogLevel::Critical));
+    }
+    let tls = match tls::tls_config(&figment) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = check_unprotected_listeners(&args, tls.is_some()) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    let tls = tls.filter(|_| !args.no_tcp);
+    if let Err(e) = check_password_endpoint(&figment, &args, tls.is_some()) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    let tls_files = match tls.as_ref().map(TlsFiles::read).transpose() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("unable to read TLS files: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let lookup = match load_lookup(&args).await {
+        Ok(x) => SharedLookup(Arc::new(RwLock::new(Arc::new(x)))),
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let limiter = RateLimiter::default();
+    let metrics = Metrics::default();
+    let relaunch = Arc::new(Relaunch::default());
+    let build = |figment| build_rocket(figment, &args, &lookup, &limiter, &metrics);
+
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket
+        && let Err(e) = start_unix_socket(build(figment.clone()), path, &args).await
+    {
+        eprintln!("unable to listen on unix socket {}: {}", path.display(), e);
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = start_listeners(&args, &lookup, &limiter, &metrics).await {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    #[cfg(unix)]
+    if tls.is_some() {
+        figment = figment
+            .merge(("shutdown.ctrlc", false))
+            .merge(("shutdown.signals", Vec::<String>::new()));
+        tokio::spawn(tls::stop_on_signal(relaunch.clone()));
+    }
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(
+        args.clone(),
+        lookup.clone(),
+        tls.clone().zip(tls_files.clone()),
+        relaunch.clone(),
+    ));
+
+    let exit_code = match args.no_tcp {
+        true => {
+            write_pid_file(&args.pid_file);
+            stop_signal().await;
+            ExitCode::SUCCESS
+        }
+        false => launch_tcp(figment, build, tls.zip(tls_files), relaunch).await,
+    };
+    for path in unix_sockets.into_iter().flatten() {
+        let _ = std::fs::remove_file(path);
+    }
+    exit_code
+}
+
+/// launches Rocket on TCP, and again with the new files each time the TLS files changed
+async fn launch_tcp<F>(
+    figment: Figment,
+    build: F,
+    tls: Option<(TlsConfig, TlsFiles)>,
+    relaunch: Arc<Relaunch>,
+) -> ExitCode
+where
+    F: Fn(Figment) -> rocket::Rocket<rocket::Build>,
+{
+    let (tls, mut tls_files) = tls.unzip();
+    // files of the last successful launch, to fall back to if the changed ones are rejected by Rocket
+    let mut previous_tls_files = None;
+    loop {
+        let mut figment = figment.clone();
+        if let Some((tls, files)) = tls.as_ref().zip(tls_files.as_ref()) {
+            figment = figment.merge(("tls", files.apply(tls)));
+        }
+        let launched = relaunch.clone();
+        let rocket = build(figment).attach(AdHoc::on_liftoff("Liftoff", |rocket| {
+            Box::pin(async move {
+                let args = rocket.state::<CliArgs>().unwrap();
+                write_pid_file(&args.pid_file);
+                launched.launched(rocket.shutdown());
+            })
+        }));
+        if let Err(e) = rocket.launch().await {
+            let Some(files) = previous_tls_files.take() else {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            };
+            error!(
+                "failed to relaunch with the new TLS files, keeping the current ones: {}",
+                e
+            );
+            tls_files = Some(files);
+            continue;
+        }
+        match relaunch.take() {
+            Some(files) => previous_tls_files = tls_files.replace(files),
+            None => return ExitCode::SUCCESS,
+        }
+    }
+}
+
+/// listeners that don't check the API keys, or don't use TLS on TCP, are only started next to either if explicitly
+/// allowed. the Redis protocol checks API keys with AUTH
+fn check_unprotected_listeners(args: &CliArgs, tls: bool) -> Result<(), String> {
+    let keys = args.api_keys.is_some();
+    let listeners = [
+        (
+            "--binary-listen",
+            args.binary_listen.is_some() && (keys || tls),
+        ),
+        (
+            "--binary-unix-socket",
+            args.binary_unix_socket.is_some() && keys,
+        ),
+        ("--resp-listen", args.resp_listen.is_some() && tls),
+        ("--dns-listen", args.dns_listen.is_some() && (keys || tls)),
+        ("--grpc-listen", args.grpc_listen.is_some() && (keys || tls)),
+    ];
+    let unprotected: Vec<&str> = listeners
+        .into_iter()
+        .filter(|(_, unprotected)| *unprotected)
+        .map(|(name, _)| name)
+        .collect();
+    if unprotected.is_empty() || args.allow_unprotected_listeners {
+        return Ok(());
+    }
+    Err(format!(
+        "{} would be served without the API keys or TLS of the HTTP API, pass --allow-unprotected-listeners to start \
+         them anyway",
+        unprotected.join(", ")
+    ))
+}
+
+/// /password is only served over TLS or on the unix socket, and with a `password` limit small enough to allocate per
+/// request
+fn check_password_endpoint(figment: &Figment, args: &CliArgs, tls: bool) -> Result<(), String> {
+    if !args.password_endpoint {
+        return Ok(());
+    }
+    if !tls && !args.no_tcp {
+        return Err(String::from(
+            "--password-endpoint requires TLS, or --no-tcp to only serve it on --unix-socket",
+        ));
+    }
+    let limits: Limits = figment
+        .extract_inner("limits")
+        .map_err(|e| format!("invalid limits: {}", e))?;
+    if limits.get("password").unwrap_or(DEFAULT_PASSWORD_LIMIT) > MAX_PASSWORD_LIMIT {
+        return Err(format!(
+            "the password limit must not be larger than {}",
+            MAX_PASSWORD_LIMIT
+        ));
+    }
+    Ok(())
+}
+
+fn merge_tls_args(mut figment: Figment, args: &CliArgs) -> Figment {
+    if let Some(path) = &args.tls_cert {
+        figment = figment.merge(("tls.certs", path));
+    }
+    if let Some(path) = &args.tls_key {
+        figment = figment.merge(("tls.key", path));
+    }
+    if let Some(path) = &args.tls_client_ca {
+        figment = figment.merge(("tls.mutual.ca_certs", path));
+    }
+    if args.tls_client_cert_required {
+        figment = figment.merge(("tls.mutual.mandatory", true));
+    }
+    figment
+}
+
+fn build_rocket(
+    figment: Figment,
+    args: &CliArgs,
+    lookup: &SharedLookup,
+    limiter: &RateLimiter,
+    metrics: &Metrics,
+) -> rocket::Rocket<rocket::Build> {
+    let mut routes = rocket::routes![check_hash, info, metrics::metrics];
+    if args.password_endpoint {
+        routes.extend(rocket::routes![check_password]);
+    }
+    rocket::custom(figment)
+        .attach(Shield::new())
+        .attach(CountRequests)
+        .manage(lookup.clone())
+        .manage(limiter.clone())
+        .manage(metrics.clone())
+        .manage(args.clone())
+        .mount("/", routes)
+}
+
+/// serves the routes of `rocket` on the unix socket of --unix-socket in the background
+#[cfg(unix)]
+async fn start_unix_socket(
+    rocket: rocket::Rocket<rocket::Build>,
+    path: &std::path::Path,
+    args: &CliArgs,
+) -> Result<(), String> {
+    let (mode, owner) = socket_permissions(args)?;
+    // launching the local client installs Rocket's logger, so it comes first
+    let client = rocket::local::asynchronous::Client::untracked(rocket)
+        .await
+        .map_err(|e| e.to_string())?;
+    let listener = unix::bind(path, mode, owner).map_err(|e| e.to_string())?;
+    tokio::spawn(unix::serve(listener, client));
+    Ok(())
+}
+
+/// serves the binary and Redis protocols, DNS and gRPC in the background
+async fn start_listeners(
+    args: &CliArgs,
+    lookup: &SharedLookup,
+    limiter: &RateLimiter,
+    metrics: &Metrics,
+) -> Result<(), String> {
+    const BINARY: &str = "binary protocol";
+    const RESP: &str = "Redis protocol";
+    const DNS: &str = "DNS";
+    let bind_error = |addr: &dyn std::fmt::Display, e: std::io::Error| {
+        format!("unable to listen on {}: {}", addr, e)
+    };
+    if let Some(addr) = args.binary_listen {
+        let listener = listen::bind_tcp(addr, BINARY)
+            .await
+            .map_err(|e| bind_error(&addr, e))?;
+        let lookup = lookup.clone();
+        tokio::spawn(listen::serve_tcp(listener, BINARY, move |x| {
+            binary::serve_connection(x, lookup.clone())
+        }));
+    }
+    if let Some(addr) = args.resp_listen {
+        let listener = listen::bind_tcp(addr, RESP)
+            .await
+            .map_err(|e| bind_error(&addr, e))?;
+        let (lookup, limiter, metrics) = (lookup.clone(), limiter.clone(), metrics.clone());
+        tokio::spawn(listen::serve_tcp(listener, RESP, move |x| {
+            resp::serve_connection(x, lookup.clone(), limiter.clone(), metrics.clone())
+        }));
+    }
+    if let Some(addr) = args.dns_listen {
+        let zone = Arc::new(args.dns_zone.parse::<dns::Zone>()?);
+        let socket = tokio::net::UdpSocket::bind(addr)
+            .await
+            .map_err(|e| bind_error(&addr, e))?;
+        let listener = listen::bind_tcp(addr, DNS)
+            .await
+            .map_err(|e| bind_error(&addr, e))?;
+        tokio::spawn(dns::serve_udp(socket, zone.clone(), lookup.clone()));
+        let lookup = lookup.clone();
+        tokio::spawn(listen::serve_tcp(listener, DNS, move |x| {
+            dns::serve_connection(x, zone.clone(), lookup.clone())
+        }));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = args.grpc_listen {
+        let listener = listen::bind_tcp(addr, "gRPC")
+            .await
+            .map_err(|e| bind_error(&addr, e))?;
+        tokio::spawn(grpc::serve(listener, lookup.clone()));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.binary_unix_socket {
+        let (mode, owner) = socket_permissions(args)?;
+        let listener = unix::bind(path, mode, owner).map_err(|e| bind_error(&path.display(), e))?;
+        let lookup = lookup.clone();
+        tokio::spawn(listen::serve_unix(listener, BINARY, move |x| {
+            binary::serve_connection(x, lookup.clone())
+        }));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.resp_unix_socket {
+        let (mode, owner) = socket_permissions(args)?;
+        let listener = unix::bind(path, mode, owner).map_err(|e| bind_error(&path.display(), e))?;
+        let (lookup, limiter, metrics) = (lookup.clone(), limiter.clone(), metrics.clone());
+        tokio::spawn(listen::serve_unix(listener, RESP, move |x| {
+            resp::serve_connection(x, lookup.clone(), limiter.clone(), metrics.clone())
+        }));
+    }
+    Ok(())
+}
+
+/// mode and owner of the unix sockets
+#[cfg(unix)]
+fn socket_permissions(args: &CliArgs) -> Result<(u32, Option<unix::SocketOwner>), String> {
+    let mode = unix::parse_mode(&args.unix_socket_mode)?;
+    let owner = match &args.unix_socket_owner {
+        Some(owner) => Some(owner.parse::<unix::SocketOwner>()?),
+        None => None,
+    };
+    Ok((mode, owner))
+}
+
+/// waits for ctrl+c or SIGTERM
+#[cfg(unix)]
+async fn stop_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("unable to listen for SIGTERM: {:?}", e);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => warn!("received SIGINT, shutting down"),
+        _ = terminate.recv() => warn!("received SIGTERM, shutting down"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn stop_signal() {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => warn!("received ctrl+c, shutting down"),
+        Err(e) => {
+            error!("unable to listen for ctrl+c: {:?}", e);
+            std::future::pending().await
+        }
+    }
+}
+
+fn write_pid_file(pid_file: &Option<String>) {
+    let Some(pid_file) = pid_file else {
+        return;
+    };
+    if let Err(e) = std::fs::write(pid_file, std::process::id().to_string()) {
+        error!("unable to write pid file {}: {:?}", pid_file, e);
+    }
+}
+
+/// reloads the lookup and, if TLS is enabled and its files changed, relaunches Rocket with the new files
+#[cfg(unix)]
+async fn reload_on_sighup(
+    args: CliArgs,
+    lookup: SharedLookup,
+    mut tls: Option<(TlsConfig, TlsFiles)>,
+    relaunch: Arc<Relaunch>,
+) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("unable to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("received SIGHUP, reloading");
+        match load_lookup(&args).await {
+            Ok(x) => {
+                lookup.replace(x);
+                info!("successfully reloaded filter");
+            }
+            Err(e) => error!("failed to reload, keeping current filter: {}", e),
+        }
+        if let Some((config, current)) = &mut tls {
+            tls::reload(config, current, &relaunch);
+        }
+    }
+}
+
+async fn load_lookup(args: &CliArgs) -> Result<Lookup, String> {
+    let file_name = PathBuf::from(&args.filter_path);
+    let filter = tokio::task::spawn_blocking(move || open_filter(file_name))
+        .await
+        .map_err(|e| format!("unable to read filter file: {:?}", e))??;
+    let ntlm_filter = match &args.ntlm_filter_path {
+        Some(path) => {
+            let file_name = PathBuf::from(path);
+            let filter = tokio::task::spawn_blocking(move || open_filter(file_name))
+                .await
+                .map_err(|e| format!("unable to read NTLM filter file: {:?}", e))??;
+            Some(filter)
+        }
+        None => None,
+    };
+    let sources = match &args.state_db_path {
+        Some(path) => read_sources(PathBuf::from(path)).await?,
+        None => Vec::new(),
+    };
+    let allowlist = match &args.allowlist {
+        Some(path) => open_allowlist(PathBuf::from(path))?,
+        None => Allowlist::default(),
+    };
+    let api_keys = match &args.api_keys {
+        Some(path) => {
+            let api_keys = ApiKeys::open(path.as_ref())?;
+            info!("loaded {} API keys", api_keys.len());
+            Some(api_keys)
+        }
+        None => None,
+    };
+    Ok(Lookup {
+        filter,
+        ntlm_filter,
+        allowlist,
+        api_keys,
+        sources,
+    })
+}
+
+fn open_filter(file_name: PathBuf) -> Result<PwnedFilter, String> {
+    PwnedFilter::open(file_name).map_err(|e| format!("unable to read filter file: {:?}", e))
+}
+
+fn open_allowlist(file_name: PathBuf) -> Result<Allowlist, String> {
+    let allowlist =
+        Allowlist::open(&file_name).map_err(|e| format!("unable to read allowlist: {:?}", e))?;
+    info!("loaded {} allowlist entries", allowlist.len());
+    Ok(allowlist)
+}
+
+async fn read_sources(path: PathBuf) -> Result<Vec<SourceInfo>, String> {
+    let state_db = StateDatabase::open_readonly(&path)
+        .await
+        .map_err(|e| format!("unable to open state database: {:?}", e))?;
+    let sources = match state_db.fetch_sources().await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("unable to read extra sources from state database: {:?}", e);
+            Vec::new()
+        }
+    };
+    let _ = state_db.close().await;
+    Ok(sources
+        .into_iter()
+        .map(|x| SourceInfo {
+            path: x.path,
+            kind: x.kind,
+            hashes: x.hashes,
+            last_update: x.last_update,
+        })
+        .collect())
+}
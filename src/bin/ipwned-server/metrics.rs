@@ -0,0 +1,71 @@
+//! Request counters of the query routes and the lookups of the Redis protocol, served on /metrics in the Prometheus
+//! text format.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{Request, Response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::{ClientName, MetricsClient};
+
+/// client name, protocol and status code of a counter
+type Labels = (String, &'static str, u16);
+
+/// number of responses by their labels, kept across relaunches
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<BTreeMap<Labels, u64>>>);
+
+impl Metrics {
+    pub fn count(&self, client: &str, protocol: &'static str, status: u16) {
+        let mut counters = self.0.lock().unwrap();
+        *counters
+            .entry((client.to_string(), protocol, status))
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP ipwned_requests_total Responses of the query routes and Redis lookups by client, protocol and status code.\n");
+        out.push_str("# TYPE ipwned_requests_total counter\n");
+        for ((client, protocol, status), count) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "ipwned_requests_total{{client=\"{}\",protocol=\"{}\",code=\"{}\"}} {}",
+                client.replace('\\', "\\\\").replace('"', "\\\""),
+                protocol,
+                status,
+                count
+            );
+        }
+        out
+    }
+}
+
+/// counts the responses of all requests the `Client` guard ran for
+pub struct CountRequests;
+
+#[rocket::async_trait]
+impl Fairing for CountRequests {
+    fn info(&self) -> Info {
+        Info {
+            name: "Count requests",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(client) = &req.local_cache(|| ClientName(None)).0 {
+            let metrics = req.rocket().state::<Metrics>().unwrap();
+            metrics.count(client, "http", res.status().code);
+        }
+    }
+}
+
+/// requires an API key like the query routes if --api-keys is given
+#[rocket::get("/metrics")]
+pub fn metrics(_client: MetricsClient, metrics: &rocket::State<Metrics>) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, metrics.render())
+}
@@ -0,0 +1,543 @@
+//! Redis protocol (RESP2) frontend, so services can query the filter with existing Redis clients. Keys are hex encoded
+//! SHA1 hashes. With --api-keys clients authenticate with `AUTH <key>` and each looked up hash counts against the key's
+//! rate limit. Lookup commands are counted on /metrics with the status code of the equivalent HTTP response.
+//!
+//! supported commands:
+//! - `AUTH <key>`: authenticates the connection with an API key, required for other commands than QUIT with --api-keys
+//! - `EXISTS <hash> [<hash> ...]`: number of pwned hashes, invalid hashes count as missing keys
+//! - `PWNED.CHECK <hash>`: 1 if pwned, 0 if not
+//! - `PWNED.MCHECK <hash> [<hash> ...]`: array of 1 or 0 per hash
+//! - `PING`, `ECHO`, `SELECT 0`, `QUIT` and an empty `COMMAND` reply, for clients that send them on connect
+
+use std::borrow::Cow;
+use std::io;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+
+use crate::auth::{ANONYMOUS, RateLimiter, UNAUTHORIZED};
+use crate::metrics::Metrics;
+use crate::{Lookup, SharedLookup};
+
+/// maximum length of a line, i.e. an inline command or the header of an argument
+const MAX_LINE: u64 = 64 * 1024;
+/// maximum number of arguments of a command, a PWNED.MCHECK with 65536 hashes
+const MAX_ARGS: usize = 65537;
+/// maximum length of an argument, hashes are 40 bytes but ECHO and PING take any message
+const MAX_ARG_LEN: usize = 64 * 1024;
+/// maximum length of all arguments of a command, enough for MAX_ARGS hashes
+const MAX_COMMAND_LEN: usize = 4 * 1024 * 1024;
+/// failed AUTH attempts after which the connection is closed, so keys can't be guessed quickly on one connection
+const MAX_AUTH_FAILURES: u32 = 3;
+/// arguments allocated up front, the count of the header is only trusted as far as arguments arrive
+const PREALLOCATED_ARGS: usize = 16;
+
+/// state of a connection across commands
+struct Session {
+    limiter: RateLimiter,
+    metrics: Metrics,
+    /// hash of the API key given with AUTH, checked again on each lookup so removed keys stop working on reload
+    key: Option<[u8; 20]>,
+    auth_failures: u32,
+}
+
+/// answers commands until the client closes the connection or sends QUIT. replies are flushed once no further command
+/// is buffered, so pipelined commands share writes. a malformed command is answered with an error and closes the
+/// connection, like Redis does
+pub async fn serve_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    lookup: SharedLookup,
+    limiter: RateLimiter,
+    metrics: Metrics,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut reply = Vec::new();
+    let mut session = Session {
+        limiter,
+        metrics,
+        key: None,
+        auth_failures: 0,
+    };
+    loop {
+        let args = match read_command(&mut reader).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                writer
+                    .write_all(format!("-ERR Protocol error: {}\r\n", e).as_bytes())
+                    .await?;
+                writer.flush().await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        reply.clear();
+        let quit = execute(&args, &lookup.get(), &mut session, &mut reply);
+        writer.write_all(&reply).await?;
+        if quit {
+            return writer.flush().await;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// reads a line without the trailing `\r\n`, `None` at the end of the stream
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() as u64 + 1 >= MAX_LINE {
+            true => protocol_error("too big inline request"),
+            false => io::ErrorKind::UnexpectedEof.into(),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// parses the number after the type byte of a header line
+fn parse_length(line: &[u8], max: usize, name: &str) -> io::Result<usize> {
+    std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|x| *x <= max)
+        .ok_or_else(|| protocol_error(&format!("invalid {}", name)))
+}
+
+/// reads an array of bulk strings as sent by clients, or an inline command split at whitespace as typed into telnet
+async fn read_command<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|x| x.is_ascii_whitespace())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_vec())
+                .collect(),
+        ));
+    }
+    let count = parse_length(&line, MAX_ARGS, "multibulk length")?;
+    let mut args = Vec::with_capacity(count.min(PREALLOCATED_ARGS));
+    let mut total_len = 0;
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_length(&line, MAX_ARG_LEN, "bulk length")?;
+        total_len += len;
+        if total_len > MAX_COMMAND_LEN {
+            return Err(protocol_error("too big command"));
+        }
+        let mut arg = vec![0u8; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected CRLF after bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn parse_hash(hex: &[u8]) -> Option<[u8; 20]> {
+    let mut hash = [0u8; 20];
+    match hex.len() == 40 && faster_hex::hex_decode(hex, &mut hash).is_ok() {
+        true => Some(hash),
+        false => None,
+    }
+}
+
+fn is_lookup(command: &str) -> bool {
+    matches!(command, "EXISTS" | "PWNED.CHECK" | "PWNED.MCHECK")
+}
+
+/// status code and error reply if the session may not run the command. lookups take a token of the rate limit of the
+/// key per hash, like an HTTP request, and are rejected as a whole if fewer are left. a batch larger than the burst of
+/// the key could never succeed, it is rejected with its own error
+fn check_access(
+    lookup: &Lookup,
+    session: &mut Session,
+    command: &str,
+    args: &[Vec<u8>],
+) -> Option<(u16, Cow<'static, str>)> {
+    const NOAUTH: (u16, Cow<'static, str>) =
+        (401, Cow::Borrowed("-NOAUTH Authentication required.\r\n"));
+    let api_keys = lookup.api_keys.as_ref()?;
+    if matches!(command, "AUTH" | "QUIT") {
+        return None;
+    }
+    let Some(key) = &session.key else {
+        return Some(NOAUTH);
+    };
+    if !is_lookup(command) {
+        return None;
+    }
+    let tokens = args.len().max(1) as u32;
+    match api_keys.take(&session.limiter, key, tokens) {
+        Some(true) => None,
+        Some(false) => match api_keys.burst(key) {
+            Some(burst) if tokens > burst => {
+                let error = format!(
+                    "-ERR batch of {} hashes exceeds the burst of {} of the API key\r\n",
+                    tokens, burst
+                );
+                Some((429, error.into()))
+            }
+            _ => Some((429, "-ERR rate limit exceeded\r\n".into())),
+        },
+        None => {
+            session.key = None;
+            Some(NOAUTH)
+        }
+    }
+}
+
+/// counts a lookup command on /metrics, by the name of the API key of the session
+fn count_lookup(lookup: &Lookup, session: &Session, status: u16) {
+    let client = match (&lookup.api_keys, &session.key) {
+        (None, _) => ANONYMOUS,
+        (Some(api_keys), Some(key)) => api_keys.name(key).unwrap_or(UNAUTHORIZED),
+        (Some(_), None) => UNAUTHORIZED,
+    };
+    session.metrics.count(client, "resp", status);
+}
+
+/// writes the reply of the command to `reply` and counts lookups, true if the connection should be closed
+fn execute(args: &[Vec<u8>], lookup: &Lookup, session: &mut Session, reply: &mut Vec<u8>) -> bool {
+    // the name is part of error replies, which end at the first line break
+    let name = String::from_utf8_lossy(&args[0]).replace(|x: char| x.is_control(), " ");
+    let command = name.to_ascii_uppercase();
+    let args = &args[1..];
+    if let Some((status, error)) = check_access(lookup, session, &command, args) {
+        reply.extend(error.as_bytes());
+        if is_lookup(&command) {
+            count_lookup(lookup, session, status);
+        }
+        return false;
+    }
+    let quit = run_command(&name, &command, args, lookup, session, reply);
+    if is_lookup(&command) {
+        // lookups only fail on invalid arguments
+        let status = match reply.first() {
+            Some(b'-') => 400,
+            _ => 200,
+        };
+        count_lookup(lookup, session, status);
+    }
+    quit
+}
+
+/// `execute` once the session may run the command
+fn run_command(
+    name: &str,
+    command: &str,
+    args: &[Vec<u8>],
+    lookup: &Lookup,
+    session: &mut Session,
+    reply: &mut Vec<u8>,
+) -> bool {
+    let wrong_arguments = || {
+        format!(
+            "-ERR wrong number of arguments for '{}' command\r\n",
+            name.to_ascii_lowercase()
+        )
+    };
+    let is_pwned = |hex: &[u8]| parse_hash(hex).is_some_and(|x| lookup.contains(&x));
+    match command {
+        "AUTH" => match (args, &lookup.api_keys) {
+            (_, None) => reply.extend(b"-ERR AUTH called without --api-keys configured\r\n"),
+            ([key], Some(api_keys)) => match api_keys.find(key) {
+                Some(hash) => {
+                    session.key = Some(hash);
+                    reply.extend(b"+OK\r\n");
+                }
+                None => {
+                    reply.extend(b"-WRONGPASS invalid API key\r\n");
+                    session.auth_failures += 1;
+                    return session.auth_failures >= MAX_AUTH_FAILURES;
+                }
+            },
+            _ => reply.extend(wrong_arguments().as_bytes()),
+        },
+        "EXISTS" | "PWNED.MCHECK" if args.is_empty() => reply.extend(wrong_arguments().as_bytes()),
+        "EXISTS" => {
+            let count = args.iter().filter(|x| is_pwned(x)).count();
+            reply.extend(format!(":{}\r\n", count).as_bytes());
+        }
+        "PWNED.CHECK" => match args {
+            [hex] => match parse_hash(hex) {
+                Some(hash) => {
+                    reply.extend(format!(":{}\r\n", lookup.contains(&hash) as u8).as_bytes())
+                }
+                None => reply.extend(b"-ERR invalid SHA1 hash, expected 40 hex characters\r\n"),
+            },
+            _ => reply.extend(wrong_arguments().as_bytes()),
+        },
+        "PWNED.MCHECK" => {
+            if args.iter().any(|x| parse_hash(x).is_none()) {
+                reply.extend(b"-ERR invalid SHA1 hash, expected 40 hex characters\r\n");
+                return false;
+            }
+            reply.extend(format!("*{}\r\n", args.len()).as_bytes());
+            for hex in args {
+                reply.extend(if is_pwned(hex) { b":1\r\n" } else { b":0\r\n" });
+            }
+        }
+        "PING" => match args {
+            [] => reply.extend(b"+PONG\r\n"),
+            [message] => write_bulk(reply, message),
+            _ => reply.extend(wrong_arguments().as_bytes()),
+        },
+        "ECHO" => match args {
+            [message] => write_bulk(reply, message),
+            _ => reply.extend(wrong_arguments().as_bytes()),
+        },
+        "SELECT" => match args {
+            [db] if db == b"0" => reply.extend(b"+OK\r\n"),
+            [_] => reply.extend(b"-ERR DB index is out of range\r\n"),
+            _ => reply.extend(wrong_arguments().as_bytes()),
+        },
+        "COMMAND" => reply.extend(b"*0\r\n"),
+        "QUIT" => {
+            reply.extend(b"+OK\r\n");
+            return true;
+        }
+        _ => reply.extend(format!("-ERR unknown command '{}'\r\n", name).as_bytes()),
+    }
+    false
+}
+
+fn write_bulk(reply: &mut Vec<u8>, data: &[u8]) {
+    reply.extend(format!("${}\r\n", data.len()).as_bytes());
+    reply.extend(data);
+    reply.extend(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ApiKeys;
+
+    const PWNED: &str = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3";
+    const NOT_PWNED: &str = "0000000000000000000000000000000000000000";
+
+    async fn read(input: &[u8]) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let mut reader = input;
+        read_command(&mut reader).await
+    }
+
+    fn lookup(api_keys: Option<&str>) -> Lookup {
+        let api_keys = api_keys.map(|x| ApiKeys::from_toml(x).unwrap());
+        Lookup::with_hashes(&[parse_hash(PWNED.as_bytes()).unwrap()], api_keys)
+    }
+
+    fn session() -> Session {
+        Session {
+            limiter: RateLimiter::default(),
+            metrics: Metrics::default(),
+            key: None,
+            auth_failures: 0,
+        }
+    }
+
+    /// runs an inline command, returns the reply and whether the connection is closed
+    fn run(lookup: &Lookup, session: &mut Session, command: &str) -> (String, bool) {
+        let args: Vec<Vec<u8>> = command.split(' ').map(|x| x.into()).collect();
+        let mut reply = Vec::new();
+        let quit = execute(&args, lookup, session, &mut reply);
+        (String::from_utf8(reply).unwrap(), quit)
+    }
+
+    #[tokio::test]
+    async fn inline_command() {
+        let args = read(b"  PING  hello\tworld\r\n").await.unwrap().unwrap();
+        assert_eq!(args, [&b"PING"[..], b"hello", b"world"]);
+        assert_eq!(read(b"\r\n").await.unwrap().unwrap().len(), 0);
+        assert!(read(b"").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn multibulk_command() {
+        let args = read(b"*2\r\n$6\r\nEXISTS\r\n$4\r\na\r\nb\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(args, [&b"EXISTS"[..], b"a\r\nb"]);
+        assert_eq!(read(b"*0\r\n").await.unwrap().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn oversize_command() {
+        let kind = |x: io::Result<_>| x.unwrap_err().kind();
+        let line = vec![b'a'; MAX_LINE as usize + 1];
+        assert_eq!(kind(read(&line).await), io::ErrorKind::InvalidData);
+        let args = format!("*{}\r\n", MAX_ARGS + 1);
+        assert_eq!(
+            kind(read(args.as_bytes()).await),
+            io::ErrorKind::InvalidData
+        );
+        let arg = format!("*1\r\n${}\r\n", MAX_ARG_LEN + 1);
+        assert_eq!(kind(read(arg.as_bytes()).await), io::ErrorKind::InvalidData);
+
+        let mut command = format!("*{}\r\n", MAX_ARGS).into_bytes();
+        for _ in 0..MAX_ARGS {
+            command.extend(format!("${}\r\n", MAX_ARG_LEN).as_bytes());
+            command.resize(command.len() + MAX_ARG_LEN, b'a');
+            command.extend(b"\r\n");
+            if command.len() > MAX_COMMAND_LEN + MAX_ARG_LEN {
+                break;
+            }
+        }
+        let error = read(&command).await.unwrap_err();
+        assert_eq!(error.to_string(), "too big command");
+    }
+
+    #[tokio::test]
+    async fn malformed_command() {
+        let error = read(b"*1\r\n$4\r\nPINGxx").await.unwrap_err();
+        assert_eq!(error.to_string(), "expected CRLF after bulk string");
+        let error = read(b"*1\r\n:4\r\n").await.unwrap_err();
+        assert_eq!(error.to_string(), "expected '$'");
+        let error = read(b"*x\r\n").await.unwrap_err();
+        assert_eq!(error.to_string(), "invalid multibulk length");
+        let error = read(b"*2\r\n$4\r\nPING\r\n").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = read(b"PING").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn lookups() {
+        let lookup = lookup(None);
+        let mut session = session();
+        let check = format!("PWNED.CHECK {}", PWNED);
+        assert_eq!(run(&lookup, &mut session, &check).0, ":1\r\n");
+        let mcheck = format!("pwned.mcheck {} {} {}", PWNED, NOT_PWNED, PWNED);
+        assert_eq!(
+            run(&lookup, &mut session, &mcheck).0,
+            "*3\r\n:1\r\n:0\r\n:1\r\n"
+        );
+        let mcheck = format!("PWNED.MCHECK {} invalid", PWNED);
+        assert!(
+            run(&lookup, &mut session, &mcheck)
+                .0
+                .starts_with("-ERR invalid SHA1 hash")
+        );
+        let exists = format!("EXISTS {} {} invalid", PWNED, NOT_PWNED);
+        assert_eq!(run(&lookup, &mut session, &exists).0, ":1\r\n");
+        assert_eq!(
+            run(&lookup, &mut session, "PWNED.MCHECK").0,
+            "-ERR wrong number of arguments for 'pwned.mcheck' command\r\n"
+        );
+        assert_eq!(
+            run(&lookup, &mut session, "AUTH key").0,
+            "-ERR AUTH called without --api-keys configured\r\n"
+        );
+        assert_eq!(run(&lookup, &mut session, "QUIT"), ("+OK\r\n".into(), true));
+    }
+
+    #[test]
+    fn authentication() {
+        let lookup = lookup(Some("[keys.test]\nkey = \"secret\""));
+        let mut session = session();
+        let check = format!("PWNED.CHECK {}", PWNED);
+        let noauth = "-NOAUTH Authentication required.\r\n";
+        assert_eq!(run(&lookup, &mut session, &check).0, noauth);
+        assert_eq!(run(&lookup, &mut session, "PING").0, noauth);
+        assert_eq!(
+            run(&lookup, &mut session, "AUTH wrong"),
+            ("-WRONGPASS invalid API key\r\n".into(), false)
+        );
+        assert_eq!(run(&lookup, &mut session, "AUTH secret").0, "+OK\r\n");
+        assert_eq!(run(&lookup, &mut session, &check).0, ":1\r\n");
+
+        let mut session = self::session();
+        for _ in 1..MAX_AUTH_FAILURES {
+            assert!(!run(&lookup, &mut session, "AUTH wrong").1);
+        }
+        assert!(run(&lookup, &mut session, "AUTH wrong").1);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let lookup = lookup(Some(
+            "[keys.test]\nkey = \"secret\"\nrate = 0.001\nburst = 3",
+        ));
+        let mut session = session();
+        run(&lookup, &mut session, "AUTH secret");
+        let exceeded = "-ERR rate limit exceeded\r\n";
+        let mcheck = format!("PWNED.MCHECK {} {} {} {}", PWNED, PWNED, PWNED, PWNED);
+        assert_eq!(
+            run(&lookup, &mut session, &mcheck).0,
+            "-ERR batch of 4 hashes exceeds the burst of 3 of the API key\r\n"
+        );
+        let mcheck = format!("PWNED.MCHECK {} {}", PWNED, NOT_PWNED);
+        assert_eq!(run(&lookup, &mut session, &mcheck).0, "*2\r\n:1\r\n:0\r\n");
+        let exists = format!("EXISTS {} {}", PWNED, NOT_PWNED);
+        assert_eq!(run(&lookup, &mut session, &exists).0, exceeded);
+        let check = format!("PWNED.CHECK {}", PWNED);
+        assert_eq!(run(&lookup, &mut session, &check).0, ":1\r\n");
+        assert_eq!(run(&lookup, &mut session, &check).0, exceeded);
+        assert_eq!(run(&lookup, &mut session, "PING").0, "+PONG\r\n");
+    }
+
+    #[test]
+    fn metrics() {
+        let lookup = lookup(Some(
+            "[keys.test]\nkey = \"secret\"\nrate = 0.001\nburst = 2",
+        ));
+        let mut session = session();
+        let check = format!("PWNED.CHECK {}", PWNED);
+        run(&lookup, &mut session, &check);
+        run(&lookup, &mut session, "AUTH secret");
+        run(&lookup, &mut session, "PING");
+        run(&lookup, &mut session, &check);
+        run(&lookup, &mut session, "PWNED.CHECK invalid");
+        run(&lookup, &mut session, &check);
+        let mcheck = format!("PWNED.MCHECK {} {} {}", PWNED, PWNED, PWNED);
+        run(&lookup, &mut session, &mcheck);
+        let metrics = session.metrics.render();
+        for line in [
+            "ipwned_requests_total{client=\"test\",protocol=\"resp\",code=\"200\"} 1\n",
+            "ipwned_requests_total{client=\"test\",protocol=\"resp\",code=\"400\"} 1\n",
+            "ipwned_requests_total{client=\"test\",protocol=\"resp\",code=\"429\"} 2\n",
+            "ipwned_requests_total{client=\"unauthorized\",protocol=\"resp\",code=\"401\"} 1\n",
+        ] {
+            assert!(metrics.contains(line), "{}", metrics);
+        }
+        assert_eq!(metrics.matches("ipwned_requests_total{").count(), 4);
+
+        let lookup = self::lookup(None);
+        let mut session = self::session();
+        run(&lookup, &mut session, &check);
+        let metrics = session.metrics.render();
+        assert!(metrics.contains(
+            "ipwned_requests_total{client=\"anonymous\",protocol=\"resp\",code=\"200\"} 1\n"
+        ));
+    }
+}
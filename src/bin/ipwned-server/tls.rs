@@ -0,0 +1,267 @@
+//! Rocket reads its TLS certificates once at launch. To pick up renewed certificates on SIGHUP the server reads the
+//! files itself, passes their contents to Rocket and relaunches it when they changed.
+
+use log::{error, info};
+use rocket::Shutdown;
+use rocket::config::{MutualTls, TlsConfig};
+use rocket::figment::Figment;
+use std::io;
+use std::sync::Mutex;
+
+const PEM_CERTIFICATE: &[u8] = b"-----BEGIN CERTIFICATE-----";
+
+/// the TLS config of Rocket.toml, with the paths of the CLI options merged in. `None` if TLS is not configured
+pub fn tls_config(figment: &Figment) -> Result<Option<TlsConfig>, String> {
+    match figment.extract_inner("tls") {
+        Ok(x) => Ok(x),
+        Err(e) if e.missing() => Ok(None),
+        Err(e) => Err(format!("invalid TLS config: {}", e)),
+    }
+}
+
+/// contents of the certificate chain, private key and client CA files
+#[derive(Clone, PartialEq)]
+pub struct TlsFiles {
+    certs: Vec<u8>,
+    key: Vec<u8>,
+    ca_certs: Option<Vec<u8>>,
+}
+
+impl TlsFiles {
+    /// fails if the certificate files don't contain a PEM certificate, which Rocket would accept as an empty chain
+    pub fn read(config: &TlsConfig) -> io::Result<TlsFiles> {
+        let files = TlsFiles {
+            certs: config.certs().either(read_file, |x| Ok(x.to_vec()))?,
+            key: config.key().either(read_file, |x| Ok(x.to_vec()))?,
+            ca_certs: match config.mutual() {
+                Some(mutual) => Some(mutual.ca_certs().either(read_file, |x| Ok(x.to_vec()))?),
+                None => None,
+            },
+        };
+        for certs in std::iter::once(&files.certs).chain(&files.ca_certs) {
+            if !certs
+                .windows(PEM_CERTIFICATE.len())
+                .any(|x| x == PEM_CERTIFICATE)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no PEM encoded certificate found",
+                ));
+            }
+        }
+        Ok(files)
+    }
+
+    /// `config` with the files replaced by the contents read from them
+    pub fn apply(&self, config: &TlsConfig) -> TlsConfig {
+        let tls = TlsConfig::from_bytes(&self.certs, &self.key)
+            .with_ciphers(config.ciphers())
+            .with_preferred_server_cipher_order(config.prefer_server_cipher_order());
+        match (config.mutual(), &self.ca_certs) {
+            (Some(mutual), Some(ca_certs)) => {
+                tls.with_mutual(MutualTls::from_bytes(ca_certs).mandatory(mutual.mandatory))
+            }
+            _ => tls,
+        }
+    }
+}
+
+fn read_file(path: std::path::PathBuf) -> io::Result<Vec<u8>> {
+    std::fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+#[derive(Default)]
+struct RelaunchState {
+    shutdown: Option<Shutdown>,
+    pending: Option<TlsFiles>,
+    stopped: bool,
+}
+
+/// hands changed TLS files from the SIGHUP handler to the launch loop
+#[derive(Default)]
+pub struct Relaunch(Mutex<RelaunchState>);
+
+impl Relaunch {
+    /// called on liftoff. shuts the new instance down right away if files changed while it was launching
+    pub fn launched(&self, shutdown: Shutdown) {
+        let mut state = self.0.lock().unwrap();
+        if state.pending.is_some() || state.stopped {
+            shutdown.clone().notify();
+        }
+        state.shutdown = Some(shutdown);
+    }
+
+    /// shuts the running instance down gracefully to relaunch it with `files`
+    pub fn request(&self, files: TlsFiles) {
+        let mut state = self.0.lock().unwrap();
+        state.pending = Some(files);
+        if let Some(shutdown) = state.shutdown.take() {
+            shutdown.notify();
+        }
+    }
+
+    /// shuts the running instance down gracefully without relaunching it
+    pub fn stop(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.stopped = true;
+        if let Some(shutdown) = state.shutdown.take() {
+            shutdown.notify();
+        }
+    }
+
+    /// files to relaunch with after Rocket stopped, `None` if it was shut down for good
+    pub fn take(&self) -> Option<TlsFiles> {
+        let mut state = self.0.lock().unwrap();
+        match state.stopped {
+            true => None,
+            false => state.pending.take(),
+        }
+    }
+}
+
+/// reads the TLS files again and relaunches with them if they changed, `current` is kept if they can't be read
+pub fn reload(config: &TlsConfig, current: &mut TlsFiles, relaunch: &Relaunch) {
+    match TlsFiles::read(config) {
+        Ok(files) if files == *current => {}
+        Ok(files) => {
+            info!("TLS files changed, relaunching");
+            *current = files.clone();
+            relaunch.request(files);
+        }
+        Err(e) => error!("failed to read TLS files, keeping the current ones: {}", e),
+    }
+}
+
+/// Rocket's own signal handling can't be used with relaunching, the handler of every instance keeps running after
+/// its instance stopped. Instead ctrl+c and SIGTERM stop the current instance here.
+#[cfg(unix)]
+pub async fn stop_on_signal(relaunch: std::sync::Arc<Relaunch>) {
+    crate::stop_signal().await;
+    relaunch.stop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::path::PathBuf;
+
+    const CERT: &str = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ipwned_tls_test_{}_{}", std::process::id(), name))
+    }
+
+    fn files() -> TlsFiles {
+        TlsFiles {
+            certs: CERT.into(),
+            key: b"key".to_vec(),
+            ca_certs: None,
+        }
+    }
+
+    async fn shutdown() -> Shutdown {
+        let figment = rocket::Config::figment().merge(("log_level", "off"));
+        rocket::custom(figment).ignite().await.unwrap().shutdown()
+    }
+
+    fn notified(shutdown: &Shutdown) -> bool {
+        shutdown.clone().now_or_never().is_some()
+    }
+
+    #[test]
+    fn reload_files() {
+        let (certs, key) = (temp_path("reload.crt"), temp_path("reload.key"));
+        std::fs::write(&certs, CERT).unwrap();
+        std::fs::write(&key, "key").unwrap();
+        let config = TlsConfig::from_paths(&certs, &key);
+        let mut current = TlsFiles::read(&config).unwrap();
+        let relaunch = Relaunch::default();
+
+        reload(&config, &mut current, &relaunch);
+        assert!(relaunch.take().is_none());
+
+        std::fs::write(&key, "new key").unwrap();
+        reload(&config, &mut current, &relaunch);
+        assert!(current.key == b"new key");
+        assert!(relaunch.take() == Some(current.clone()));
+        assert!(relaunch.take().is_none());
+
+        std::fs::remove_file(&key).unwrap();
+        reload(&config, &mut current, &relaunch);
+        assert!(current.key == b"new key");
+        assert!(relaunch.take().is_none());
+
+        // a file without a certificate is rejected like an unreadable one
+        std::fs::write(&key, "new key").unwrap();
+        std::fs::write(&certs, "not a certificate").unwrap();
+        reload(&config, &mut current, &relaunch);
+        assert!(current.certs == CERT.as_bytes());
+        assert!(relaunch.take().is_none());
+        std::fs::remove_file(&certs).unwrap();
+        std::fs::remove_file(&key).unwrap();
+    }
+
+    #[test]
+    fn compare_files() {
+        let files = files();
+        let with_ca = TlsFiles {
+            ca_certs: Some(CERT.into()),
+            ..files.clone()
+        };
+        assert!(files == files.clone());
+        assert!(files != with_ca);
+        assert!(
+            files
+                != TlsFiles {
+                    key: b"other key".to_vec(),
+                    ..files.clone()
+                }
+        );
+        let config = TlsConfig::from_bytes(CERT.as_bytes(), b"key")
+            .with_mutual(MutualTls::from_bytes(CERT.as_bytes()));
+        assert!(TlsFiles::read(&config).unwrap() == with_ca);
+    }
+
+    #[rocket::async_test]
+    async fn relaunch_running() {
+        let relaunch = Relaunch::default();
+        let shutdown = shutdown().await;
+        relaunch.launched(shutdown.clone());
+        assert!(!notified(&shutdown));
+        assert!(relaunch.take().is_none());
+
+        let files = files();
+        relaunch.request(files.clone());
+        assert!(notified(&shutdown));
+        assert!(relaunch.take() == Some(files));
+    }
+
+    #[rocket::async_test]
+    async fn relaunch_while_launching() {
+        // files changed before the new instance lifted off, it is shut down right away
+        let relaunch = Relaunch::default();
+        let files = files();
+        relaunch.request(files.clone());
+        let shutdown = shutdown().await;
+        relaunch.launched(shutdown.clone());
+        assert!(notified(&shutdown));
+        assert!(relaunch.take() == Some(files));
+    }
+
+    #[rocket::async_test]
+    async fn stop() {
+        let relaunch = Relaunch::default();
+        let shutdown = shutdown().await;
+        relaunch.launched(shutdown.clone());
+        relaunch.request(files());
+        relaunch.stop();
+        assert!(notified(&shutdown));
+        assert!(relaunch.take().is_none());
+
+        // an instance launching after the stop is shut down as well
+        let shutdown = self::shutdown().await;
+        relaunch.launched(shutdown.clone());
+        assert!(notified(&shutdown));
+    }
+}
@@ -0,0 +1,219 @@
+//! HTTP on a unix domain socket. Rocket 0.5 only listens on TCP, so connections on the socket are served by hyper and
+//! every request is dispatched to a local Rocket client, which runs the same routes, guards and fairings. Unlike on TCP
+//! request bodies are read completely before dispatching, up to `MAX_BODY`, and responses are buffered as well.
+
+use log::{error, info};
+use rocket::http::hyper::body::HttpBody;
+use rocket::http::hyper::server::conn::Http;
+use rocket::http::hyper::{self, Body, service::service_fn};
+use rocket::http::{Header, Method};
+use rocket::local::asynchronous::Client;
+use std::convert::Infallible;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use zeroize::Zeroizing;
+
+/// requests with a larger body are answered with 413 without dispatching them
+const MAX_BODY: usize = 64 * 1024;
+
+/// owner of the socket file, given as `user`, `user:group` or `:group` by name or id
+#[derive(Clone, Copy)]
+pub struct SocketOwner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl FromStr for SocketOwner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = s.split_once(':').unwrap_or((s, ""));
+        let uid = match user {
+            "" => None,
+            user => Some(user.parse().or_else(|_| lookup_id(user, false))?),
+        };
+        let gid = match group {
+            "" => None,
+            group => Some(group.parse().or_else(|_| lookup_id(group, true))?),
+        };
+        Ok(SocketOwner { uid, gid })
+    }
+}
+
+/// uid of a user or gid of a group name
+fn lookup_id(name: &str, group: bool) -> Result<u32, String> {
+    let kind = if group { "group" } else { "user" };
+    let c_name = CString::new(name).map_err(|_| format!("invalid {} name {}", kind, name))?;
+    // SAFETY: both return NULL or a pointer to a static entry, which is read before any other call
+    let id = unsafe {
+        match group {
+            true => libc::getgrnam(c_name.as_ptr()).as_ref().map(|x| x.gr_gid),
+            false => libc::getpwnam(c_name.as_ptr()).as_ref().map(|x| x.pw_uid),
+        }
+    };
+    id.ok_or_else(|| format!("unknown {} {}", kind, name))
+}
+
+/// parses the permissions of the socket file as octal number
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|x| *x <= 0o777)
+        .ok_or_else(|| format!("invalid mode {}, expected an octal number like 660", s))
+}
+
+/// binds the socket, replacing a socket file left behind by a previous run
+pub fn bind(path: &Path, mode: u32, owner: Option<SocketOwner>) -> io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    if let Some(owner) = owner {
+        std::os::unix::fs::chown(path, owner.uid, owner.gid)?;
+    }
+    info!("listening on unix socket {}", path.display());
+    Ok(listener)
+}
+
+pub async fn serve(listener: UnixListener, client: Client) {
+    let client = Arc::new(client);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("unable to accept connection on unix socket: {:?}", e);
+                continue;
+            }
+        };
+        let client = client.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| dispatch(client.clone(), req));
+            // errors are closed connections and malformed requests, nothing to act on
+            let _ = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await;
+        });
+    }
+}
+
+/// the body is read into one zeroed buffer, so e.g. passwords on /password aren't left behind by growing it. Rocket's
+/// local client copies it once more without zeroing
+async fn dispatch(
+    client: Arc<Client>,
+    req: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let (parts, mut body) = req.into_parts();
+    let Ok(method) = Method::from_str(parts.method.as_str()) else {
+        return Ok(empty_response(405));
+    };
+    let capacity = body.size_hint().exact().map_or(MAX_BODY, |x| x as usize);
+    if capacity > MAX_BODY {
+        return Ok(empty_response(413));
+    }
+    let mut data = Zeroizing::new(vec![0u8; capacity]);
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return Ok(empty_response(400));
+        };
+        let Some(buffer) = data.get_mut(len..len + chunk.len()) else {
+            return Ok(empty_response(413));
+        };
+        buffer.copy_from_slice(&chunk);
+        len += chunk.len();
+    }
+    let uri = parts.uri.path_and_query().map_or("/", |x| x.as_str());
+    let mut local = client.req(method, uri.to_string()).body(&data[..len]);
+    for (name, value) in &parts.headers {
+        // like Rocket on TCP, header values that aren't UTF-8 are dropped
+        if let Ok(value) = std::str::from_utf8(value.as_bytes()) {
+            local.add_header(Header::new(name.as_str().to_string(), value.to_string()));
+        }
+    }
+    drop(data);
+    let response = local.dispatch().await;
+    let mut builder = hyper::Response::builder().status(response.status().code);
+    for header in response.headers().iter() {
+        builder = builder.header(header.name().as_str(), header.value());
+    }
+    let body = response.into_bytes().await.unwrap_or_default();
+    Ok(builder
+        .body(Body::from(body))
+        .unwrap_or_else(|_| empty_response(500)))
+}
+
+fn empty_response(status: u16) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[rocket::post("/echo", data = "<body>")]
+    fn echo(body: Vec<u8>) -> Vec<u8> {
+        body
+    }
+
+    async fn request(path: &Path, request: &[u8]) -> String {
+        let mut stream = UnixStream::connect(path).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let figment = rocket::Config::figment().merge(("log_level", "off"));
+        let rocket = rocket::custom(figment).mount("/", rocket::routes![echo]);
+        let client = Client::untracked(rocket).await.unwrap();
+        let path =
+            std::env::temp_dir().join(format!("ipwned_unix_test_{}.sock", std::process::id()));
+        let listener = bind(&path, 0o600, None).unwrap();
+        tokio::spawn(serve(listener, client));
+
+        let response = request(
+            &path,
+            b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        let response = request(
+            &path,
+            b"POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+              3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n",
+        )
+        .await;
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        let response = request(
+            &path,
+            b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+
+        let response = request(
+            &path,
+            b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 70000\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Compact binary protocol of `ipwned-server --binary-listen`, for clients doing many lookups where HTTP's per request
+//! overhead dominates.
+//!
+//! A request is the byte length of its hashes as big endian `u32`, followed by the raw 20 byte SHA1 hashes, at most
+//! [`MAX_HASHES`] of them. The server answers with one byte per hash, [`PWNED`] or [`NOT_PWNED`], and without a length
+//! prefix as the client knows the number of hashes. Requests can be pipelined, the answers are sent in the order of the
+//! requests. The server closes the connection on a length that is not a multiple of 20 or above the maximum.
+
+use sha1::{Digest, Sha1};
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// size of a SHA1 hash in a request
+pub const HASH_SIZE: usize = 20;
+/// maximum number of hashes in one request
+pub const MAX_HASHES: usize = 65536;
+/// answer byte of a hash in the filter
+pub const PWNED: u8 = 1;
+/// answer byte of a hash not in the filter
+pub const NOT_PWNED: u8 = 0;
+
+/// hashes per request of [`Client::contains_many`]
+const BATCH_HASHES: usize = 8192;
+/// requests of [`Client::contains_many`] that may be unanswered before sending more. the server blocks once the answers
+/// don't fit into the socket buffers, so the client has to read them before writing more
+const BATCH_WINDOW: usize = 4;
+
+/// Reference client of the binary protocol over any stream, e.g. TCP or a unix socket.
+///
+/// ```no_run
+/// use ipwned_localdb::binary_protocol::Client;
+///
+/// let mut client = Client::connect("127.0.0.1:7661")?;
+/// if client.contains_password("hunter2")? {
+///     println!("password is pwned");
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Client<S: Read + Write> {
+    stream: BufWriter<S>,
+    /// number of hashes of the requests sent but not received yet
+    pending: VecDeque<usize>,
+}
+
+impl Client<TcpStream> {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Client<TcpStream>> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Client::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl Client<std::os::unix::net::UnixStream> {
+    pub fn connect_unix<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> io::Result<Client<std::os::unix::net::UnixStream>> {
+        Ok(Client::new(std::os::unix::net::UnixStream::connect(path)?))
+    }
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        Client {
+            stream: BufWriter::new(stream),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// whether the SHA1 hash (raw bytes, not hex) is pwned
+    pub fn contains(&mut self, sha1: &[u8; 20]) -> io::Result<bool> {
+        Ok(self.contains_many(std::slice::from_ref(sha1))?[0])
+    }
+
+    /// whether the SHA1 hash of the password is pwned, the password itself is not sent
+    pub fn contains_password<P: AsRef<[u8]>>(&mut self, password: P) -> io::Result<bool> {
+        self.contains(&Sha1::digest(password.as_ref()).into())
+    }
+
+    /// one answer per hash, the hashes are sent in pipelined requests. fails if answers of [`Client::send`] are still
+    /// pending
+    pub fn contains_many(&mut self, hashes: &[[u8; 20]]) -> io::Result<Vec<bool>> {
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "answers of previous requests are pending",
+            ));
+        }
+        let mut answers = Vec::with_capacity(hashes.len());
+        for chunk in hashes.chunks(BATCH_HASHES) {
+            if self.pending.len() >= BATCH_WINDOW {
+                answers.extend(self.receive()?);
+            }
+            self.send(chunk)?;
+        }
+        while !self.pending.is_empty() {
+            answers.extend(self.receive()?);
+        }
+        Ok(answers)
+    }
+
+    /// queues a request without waiting for its answer, it is sent when the buffer is full or on [`Client::receive`]
+    pub fn send(&mut self, hashes: &[[u8; 20]]) -> io::Result<()> {
+        if hashes.len() > MAX_HASHES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("more than {} hashes in one request", MAX_HASHES),
+            ));
+        }
+        self.stream
+            .write_all(&((hashes.len() * HASH_SIZE) as u32).to_be_bytes())?;
+        for hash in hashes {
+            self.stream.write_all(hash)?;
+        }
+        self.pending.push_back(hashes.len());
+        Ok(())
+    }
+
+    /// answers of the oldest request sent with [`Client::send`]
+    pub fn receive(&mut self) -> io::Result<Vec<bool>> {
+        let Some(len) = self.pending.pop_front() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no request pending",
+            ));
+        };
+        self.stream.flush()?;
+        let mut answers = vec![0u8; len];
+        self.stream.get_mut().read_exact(&mut answers)?;
+        answers
+            .into_iter()
+            .map(|x| match x {
+                PWNED => Ok(true),
+                NOT_PWNED => Ok(false),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid answer {}", x),
+                )),
+            })
+            .collect()
+    }
+
+    /// number of requests sent with [`Client::send`] whose answers were not received yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    /// answers every hash whose first byte is odd as pwned, until the client closes the connection
+    fn serve(mut stream: UnixStream) {
+        let mut len = [0u8; 4];
+        while stream.read_exact(&mut len).is_ok() {
+            let mut hashes = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut hashes).unwrap();
+            let answers: Vec<u8> = hashes.chunks(HASH_SIZE).map(|x| x[0] & 1).collect();
+            stream.write_all(&answers).unwrap();
+        }
+    }
+
+    #[test]
+    fn contains_many_larger_than_socket_buffers() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve(server));
+        let hashes: Vec<[u8; 20]> = (0..1_000_000u32)
+            .map(|i| {
+                let mut hash = [0u8; 20];
+                hash[..4].copy_from_slice(&i.to_le_bytes());
+                hash
+            })
+            .collect();
+        let mut client = Client::new(client);
+        let answers = client.contains_many(&hashes).unwrap();
+        assert_eq!(answers.len(), hashes.len());
+        assert!(answers.iter().enumerate().all(|(i, x)| *x == (i % 2 == 1)));
+        assert_eq!(client.pending(), 0);
+        drop(client);
+        server.join().unwrap();
+    }
+}
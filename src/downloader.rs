@@ -1,6 +1,8 @@
 use crate::misc::DownloadError;
+use crate::pwned_filter::HashType;
 use bytes::Bytes;
-use reqwest::Client;
+use log::info;
+use reqwest::{Client, Url};
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -8,29 +10,38 @@ use tokio::time::sleep;
 pub struct DownloadResult {
     pub data: Bytes,
     pub etag: Option<String>,
+    pub last_modified: Option<String>,
 }
 
 pub async fn download_retry(
     client: &Client,
-    base_url: &String,
-    prefix: &String,
+    base_url: &str,
+    prefix: &str,
+    hash_type: HashType,
     etag: Option<String>,
     max_retries: u16,
 ) -> Result<DownloadResult, DownloadError> {
     let mut timeout: f32 = 0.5;
     let mut res = Err(DownloadError { status_code: None });
-    let mut url = base_url.to_owned();
-    url.push_str(prefix);
+    let mut url = Url::parse(&format!("{}{}", base_url, prefix))
+        .map_err(|_| DownloadError { status_code: None })?;
+    if hash_type == HashType::Ntlm {
+        url.query_pairs_mut().append_pair("mode", "ntlm");
+    }
     for i in 0..max_retries {
-        res = download_remote_hashlist(client, &url, &etag).await;
+        res = download_remote_hashlist(client, url.as_str(), &etag).await;
         if res.is_ok() {
             return res;
         }
-        let res_ref = res.clone();
-        if res_ref.err().unwrap().status_code.unwrap_or(0) == 304 {
+        let status_code = res.as_ref().err().unwrap().status_code;
+        if status_code.unwrap_or(0) == 304 {
             return res;
         }
         if i < max_retries - 1 {
+            info!(
+                range = prefix, status_code = status_code, retry = i + 1;
+                "failed to download range {}, retry {} in {}s", prefix, i + 1, timeout
+            );
             sleep(Duration::from_secs_f32(timeout)).await;
             timeout *= 2.;
         }
@@ -40,7 +51,7 @@ pub async fn download_retry(
 
 pub async fn download_remote_hashlist(
     client: &Client,
-    url: &String,
+    url: &str,
     etag: &Option<String>,
 ) -> Result<DownloadResult, DownloadError> {
     let mut req = client.get(url);
@@ -50,18 +61,28 @@ pub async fn download_remote_hashlist(
     let resp = req.send().await?;
     let status = resp.status().as_u16();
     if status == 200 {
-        // FIXME: etag may have leading W/ - strip?
         let etag = resp
             .headers()
             .get("etag")
-            .map_or(None, |x| Some(x.to_str().ok()?.to_string()));
+            .and_then(|x| Some(normalize_etag(x.to_str().ok()?)));
+        let last_modified = resp
+            .headers()
+            .get("last-modified")
+            .and_then(|x| Some(x.to_str().ok()?.to_string()));
         let body = resp.bytes().await?;
         return Ok(DownloadResult {
             data: body.clone(),
-            etag: etag,
+            etag,
+            last_modified,
         });
     }
     Err(DownloadError {
         status_code: Some(status),
     })
 }
+
+/// strips the weak validator prefix, so etags compare equal when the CDN switches between weak and strong etags.
+/// If-None-Match uses weak comparison, so the normalized etag still matches either form
+pub fn normalize_etag(etag: &str) -> String {
+    etag.strip_prefix("W/").unwrap_or(etag).to_string()
+}
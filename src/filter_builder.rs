@@ -1,21 +1,72 @@
-use crate::parse::parse_file;
+use crate::parse::{
+    hash_plaintext_list, hash_plaintext_ntlm_list, parse_file, parse_ntlm_file, parse_sha1_list,
+};
 use bytes::Bytes;
-use ciborium;
 use log::{debug, error, info, trace, warn};
-use qfilter;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::fs::File;
+use std::io;
 use std::io::ErrorKind::NotFound;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tokio::sync::mpsc;
 
 const CHANNEL_BUFF_SIZE: usize = 50;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    /// hash suffixes of a single range as returned by the range API, id is the range prefix
+    Range,
+    /// full SHA1 hashes in hex, optionally followed by :COUNT, id is the source id
+    Sha1Hex,
+    /// one plaintext password per line, hashed locally, id is the source id
+    Plaintext,
+    /// NTLM hash suffixes of a single range as returned by the range API with mode=ntlm, id is the range prefix
+    NtlmRange,
+    /// one plaintext password per line, NTLM hashed locally, id is the source id
+    NtlmPlaintext,
+}
+
+impl ListFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListFormat::Range => "range",
+            ListFormat::Sha1Hex => "sha1",
+            ListFormat::Plaintext => "plaintext",
+            ListFormat::NtlmRange => "ntlm-range",
+            ListFormat::NtlmPlaintext => "ntlm-plaintext",
+        }
+    }
+
+    /// whether the id is a range prefix instead of a source id
+    pub fn is_range(&self) -> bool {
+        matches!(self, ListFormat::Range | ListFormat::NtlmRange)
+    }
+}
+
+/// where a hash list came from, passed through to the result to be stored once the list was processed
+#[derive(Debug, Clone, Default)]
+pub struct ListMeta {
+    pub etag: Option<String>,
+    /// hex encoded SHA1 digest of the list
+    pub digest: Option<String>,
+    pub size: u64,
+    /// HTTP status of the download
+    pub status: Option<u16>,
+    pub last_modified: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct HashList {
     pub id: u32,
     pub data: Bytes,
-    pub etag: Option<String>,
+    pub meta: ListMeta,
+    pub format: ListFormat,
+    /// drop the list if it only partly parses instead of adding the parsed hashes, for lists that are not downloaded
+    /// again, so they are not recorded as processed
+    pub reject_partial: bool,
 }
 
 #[derive(Debug)]
@@ -23,38 +74,93 @@ pub struct FilterResult {
     pub id: u32,
     pub total: u32,
     pub added: u32,
-    pub etag: Option<String>,
+    pub meta: ListMeta,
+    pub format: ListFormat,
+    /// allowlisted hashes found in this list, these were not added to or removed from the filter
+    pub subtracted: Vec<Bytes>,
 }
 
 #[derive(Debug)]
 struct ParseResult {
     pub id: u32,
     pub hashes: Vec<Bytes>,
-    pub etag: Option<String>,
+    pub meta: ListMeta,
+    pub format: ListFormat,
+}
+
+struct FilterState {
+    filter: qfilter::Filter,
+    /// whether the filter was changed since it was last written
+    changed: bool,
+    /// number of times the filter was written
+    writes: u32,
+    /// the filter could not take any more hashes
+    capacity_exceeded: bool,
+    /// the last attempt to write the filter failed
+    write_error: bool,
+    /// number of lists that could not be parsed and were dropped
+    dropped_lists: u32,
+    /// expired allowlist entries were not added back to the filter, or it was not written since
+    restore_pending: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilterStats {
+    pub entries: u64,
+    pub capacity: u64,
+    pub max_error_rate: f64,
+    pub current_error_rate: f64,
+    pub memory_usage: usize,
+    pub writes: u32,
+    pub capacity_exceeded: bool,
+    pub write_error: bool,
+    pub dropped_lists: u32,
+    /// the expired allowlist entries given to `FilterBuilder::new` were added back and are in the filter file
+    #[serde(skip)]
+    pub restored: bool,
 }
 
 pub struct FilterBuilder {
     pub in_tx: mpsc::Sender<Option<HashList>>,
     pub out_rx: mpsc::Receiver<Option<FilterResult>>,
+    file_name: PathBuf,
+    state: Arc<Mutex<FilterState>>,
 }
 
 fn work_parse(
     in_rx: &mut mpsc::Receiver<Option<HashList>>,
     out_tx: mpsc::Sender<Option<ParseResult>>,
+    state: Arc<Mutex<FilterState>>,
 ) {
     loop {
         let list = match in_rx.blocking_recv() {
             Some(Some(x)) => x,
             _ => break,
         };
-        let hashes = parse_file(list.id, &list.data);
+        let hashes = match list.format {
+            ListFormat::Range => parse_file(list.id, &list.data),
+            ListFormat::Sha1Hex => parse_sha1_list(&list.data),
+            ListFormat::Plaintext => hash_plaintext_list(&list.data),
+            ListFormat::NtlmRange => parse_ntlm_file(list.id, &list.data),
+            ListFormat::NtlmPlaintext => hash_plaintext_ntlm_list(&list.data),
+        };
         if hashes.is_err() {
             warn!("failed to parse hash list for id {}", list.id);
+            state.lock().unwrap().dropped_lists += 1;
             continue;
         }
         let (remainder, hashes) = hashes.unwrap();
+        // at most there should be \r\n left
+        if remainder.len() > 2 && list.reject_partial {
+            state.lock().unwrap().dropped_lists += 1;
+            warn!(
+                "failed to parse hash list for id {}: {} unparsed characters",
+                list.id,
+                remainder.len()
+            );
+            continue;
+        }
         if remainder.len() > 2 {
-            // at most there should be \r\n left
             warn!(
                 "problem parsing hash list for id {}: {} unparsed characters",
                 list.id,
@@ -64,8 +170,9 @@ fn work_parse(
         trace!("work_parse: {:?}", &hashes);
         let res = ParseResult {
             id: list.id,
-            hashes: hashes,
-            etag: list.etag,
+            hashes,
+            meta: list.meta,
+            format: list.format,
         };
         if out_tx.blocking_send(Some(res)).is_err() {
             error!("INTERNAL: unexpectedly terminated parser thread channel");
@@ -84,38 +191,77 @@ fn work_parse(
     in_rx.close();
 }
 
+/// adds the parsed lists to the filter. allowlisted hashes are removed instead, which removes their fingerprint: a
+/// different pwned hash with the same fingerprint was not stored separately and is no longer found either, at about the
+/// error rate of the filter per allowlisted hash. it comes back when the allowlist entry expires and is restored
 fn work_build(
     in_rx: &mut mpsc::Receiver<Option<ParseResult>>,
     out_tx: mpsc::Sender<Option<FilterResult>>,
     file_name: PathBuf,
-    filter: &mut qfilter::Filter,
+    state: Arc<Mutex<FilterState>>,
+    allowlist: HashSet<Bytes>,
+    restore: Vec<Bytes>,
 ) {
-    let mut changed = false;
+    {
+        let state = &mut *state.lock().unwrap();
+        let mut complete = true;
+        for hash in &restore {
+            match state.filter.insert(hash) {
+                Ok(true) => state.changed = true,
+                Ok(false) => {}
+                Err(_) => {
+                    error!("unable to restore expired allowlist entry to filter");
+                    state.capacity_exceeded = true;
+                    complete = false;
+                }
+            }
+        }
+        // stays pending until the filter is written, or for good once the filter is full and an entry may be missing
+        if complete && !state.changed {
+            state.restore_pending = false;
+        }
+    }
     'mainloop: loop {
         let mut added: u32 = 0;
+        let mut subtracted = Vec::new();
         let parsed = match in_rx.blocking_recv() {
             Some(Some(x)) => x,
             _ => break,
         };
-        for hash in &parsed.hashes {
-            match filter.insert(hash) {
-                Ok(true) => added += 1,
-                Ok(false) => {}
-                Err(_) => {
-                    error!("unable to add more items to filter");
-                    break 'mainloop;
+        {
+            let state = &mut *state.lock().unwrap();
+            for hash in &parsed.hashes {
+                if allowlist.contains(hash) {
+                    // only hashes that actually appear in a list are removed, removing a false positive
+                    // would remove a different hash with the same fingerprint
+                    if state.filter.remove(hash) {
+                        state.changed = true;
+                    }
+                    subtracted.push(hash.clone());
+                    continue;
+                }
+                match state.filter.insert(hash) {
+                    Ok(true) => added += 1,
+                    Ok(false) => {}
+                    Err(_) => {
+                        error!("unable to add more items to filter");
+                        state.capacity_exceeded = true;
+                        break 'mainloop;
+                    }
                 }
             }
+            if added > 0 {
+                state.changed = true;
+            }
         }
         let res = FilterResult {
             id: parsed.id,
             total: parsed.hashes.len() as u32,
-            added: added,
-            etag: parsed.etag,
+            added,
+            meta: parsed.meta,
+            format: parsed.format,
+            subtracted,
         };
-        if added > 0 {
-            changed = true;
-        }
         if out_tx.blocking_send(Some(res)).is_err() {
             error!("INTERNAL: unexpectedly terminated builder thread channel");
             in_rx.close();
@@ -129,72 +275,149 @@ fn work_build(
         );
     }
     debug!("cleanly exiting builder thread");
-    if changed {
-        'write: {
-            let file_name_str = file_name.to_str().unwrap();
-            let mut tmp_name = String::from(file_name_str);
-            tmp_name.push_str(".new");
-            let mut writer = match File::create(&tmp_name) {
-                Ok(x) => x,
-                Err(e) => {
-                    error!("failed to open new filter file: {:?}", e);
-                    break 'write;
-                }
-            };
-            match ciborium::into_writer(filter, &mut writer) {
-                Err(e) => {
-                    error!("failed to write new filter file: {:?}", e);
-                    break 'write;
-                }
-                _ => (),
-            }
-            match std::fs::rename(&tmp_name, &file_name) {
-                Err(e) => error!(
-                    "failed to rename {} to {}: {:?}",
-                    tmp_name, file_name_str, e
-                ),
-                _ => info!("successfully created new filter file at {}", file_name_str),
-            }
-        }
-    }
+    write_filter_maybe(&file_name, &mut state.lock().unwrap());
     let _ = out_tx.blocking_send(None);
     in_rx.close();
 }
 
+/// writes the filter if it was changed since it was last written, returns whether the file was written
+fn write_filter_maybe(file_name: &Path, state: &mut FilterState) -> bool {
+    if !state.changed {
+        return false;
+    }
+    state.write_error = !write_filter(file_name, &state.filter);
+    if !state.write_error {
+        state.changed = false;
+        state.writes += 1;
+        if !state.capacity_exceeded {
+            state.restore_pending = false;
+        }
+    }
+    !state.write_error
+}
+
+/// writes the filter to a temporary file next to `file_name` and renames it, returns whether it was written
+pub fn write_filter<T: Serialize>(file_name: &Path, filter: &T) -> bool {
+    let file_name_str = file_name.to_str().unwrap();
+    let mut tmp_name = String::from(file_name_str);
+    tmp_name.push_str(".new");
+    let mut writer = match File::create(&tmp_name) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to open new filter file: {:?}", e);
+            return false;
+        }
+    };
+    if let Err(e) = ciborium::into_writer(filter, &mut writer) {
+        error!("failed to write new filter file: {:?}", e);
+        return false;
+    }
+    match std::fs::rename(&tmp_name, file_name) {
+        Err(e) => {
+            error!(
+                "failed to rename {} to {}: {:?}",
+                tmp_name, file_name_str, e
+            );
+            false
+        }
+        _ => {
+            info!("successfully created new filter file at {}", file_name_str);
+            true
+        }
+    }
+}
+
 impl FilterBuilder {
-    pub fn new(file_name: PathBuf, max_entries: u64, max_error_rate: f64) -> FilterBuilder {
-        let mut filter = Self::open_filter_maybe(&file_name, max_entries, max_error_rate);
+    /// hashes in `allowlist` are never added and are removed from the filter when they appear in a list,
+    /// hashes in `restore` are added back to the filter before processing any list. fails if the filter file exists but
+    /// can not be read
+    pub fn new(
+        file_name: PathBuf,
+        max_entries: u64,
+        max_error_rate: f64,
+        allowlist: HashSet<Bytes>,
+        restore: Vec<Bytes>,
+    ) -> io::Result<FilterBuilder> {
+        let filter = Self::open_filter_maybe(&file_name, max_entries, max_error_rate)?;
+        let state = Arc::new(Mutex::new(FilterState {
+            filter,
+            changed: false,
+            writes: 0,
+            capacity_exceeded: false,
+            write_error: false,
+            dropped_lists: 0,
+            restore_pending: !restore.is_empty(),
+        }));
         let (in_tx, mut in_rx) = mpsc::channel::<Option<HashList>>(CHANNEL_BUFF_SIZE);
         let (tx_mid, mut rx_mid) = mpsc::channel::<Option<ParseResult>>(CHANNEL_BUFF_SIZE);
         let (out_tx, out_rx) = mpsc::channel::<Option<FilterResult>>(CHANNEL_BUFF_SIZE);
         thread::Builder::new()
             .name(String::from("Parser"))
-            .spawn(move || work_parse(&mut in_rx, tx_mid))
+            .spawn({
+                let state = state.clone();
+                move || work_parse(&mut in_rx, tx_mid, state)
+            })
             .unwrap();
         thread::Builder::new()
             .name(String::from("FilterBuilder"))
-            .spawn(move || work_build(&mut rx_mid, out_tx, file_name, &mut filter))
+            .spawn({
+                let file_name = file_name.clone();
+                let state = state.clone();
+                move || work_build(&mut rx_mid, out_tx, file_name, state, allowlist, restore)
+            })
             .unwrap();
-        FilterBuilder {
-            in_tx: in_tx,
-            out_rx: out_rx,
+        Ok(FilterBuilder {
+            in_tx,
+            out_rx,
+            file_name,
+            state,
+        })
+    }
+
+    /// number of times the filter file was written, including checkpoints
+    pub fn writes(&self) -> u32 {
+        self.state.lock().unwrap().writes
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        let state = self.state.lock().unwrap();
+        FilterStats {
+            entries: state.filter.len(),
+            capacity: state.filter.capacity(),
+            max_error_rate: state.filter.max_error_ratio(),
+            current_error_rate: state.filter.current_error_ratio(),
+            memory_usage: state.filter.memory_usage(),
+            writes: state.writes,
+            capacity_exceeded: state.capacity_exceeded,
+            write_error: state.write_error,
+            dropped_lists: state.dropped_lists,
+            restored: !state.restore_pending,
         }
     }
 
-    fn open_filter_maybe(file_name: &PathBuf, max_entries: u64, max_er: f64) -> qfilter::Filter {
-        let filter_file = File::open(file_name);
-        match filter_file {
-            Err(ref e) if e.kind() == NotFound => {
-                qfilter::Filter::new(max_entries, max_er).expect("failed to initialize new filter")
-            }
-            Err(e) => panic!("unable to open filter file: {:?}", e),
-            Ok(ref fh) => {
-                let filter_maybe = ciborium::from_reader(fh);
-                if filter_maybe.is_err() {
-                    panic!("failed to read filter file: {:?}", filter_maybe.err());
-                }
-                filter_maybe.unwrap()
-            }
+    /// writes the current filter to disk if it was changed since it was last written.
+    /// returns whether the file was written
+    pub async fn checkpoint(&self) -> bool {
+        let file_name = self.file_name.clone();
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || {
+            write_filter_maybe(&file_name, &mut state.lock().unwrap())
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    fn open_filter_maybe(
+        file_name: &PathBuf,
+        max_entries: u64,
+        max_er: f64,
+    ) -> io::Result<qfilter::Filter> {
+        match File::open(file_name) {
+            Err(ref e) if e.kind() == NotFound => qfilter::Filter::new(max_entries, max_er)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())),
+            Err(e) => Err(e),
+            Ok(fh) => ciborium::from_reader(fh)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
         }
     }
 }
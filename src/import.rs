@@ -0,0 +1,130 @@
+use crate::filter_builder::{HashList, ListFormat, ListMeta};
+use crate::parse::parse_range_prefix;
+use bytes::{BufMut, BytesMut};
+use log::warn;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const READ_BUFF_SIZE: usize = 1 << 20;
+
+/// Reads a full pwnedpasswords dump (`HASH:COUNT` lines with complete hashes)
+/// and splits it into per-range hash lists in the same format the range API returns.
+pub struct DumpReader {
+    reader: BufReader<File>,
+    /// `ListFormat::Range` for SHA1 dumps or `ListFormat::NtlmRange` for NTLM dumps
+    format: ListFormat,
+    line: Vec<u8>,
+    line_no: u64,
+    pending: Option<(u32, BytesMut)>,
+}
+
+impl DumpReader {
+    pub async fn open(path: &Path, format: ListFormat) -> io::Result<DumpReader> {
+        let file = File::open(path).await?;
+        Ok(DumpReader {
+            reader: BufReader::with_capacity(READ_BUFF_SIZE, file),
+            format,
+            line: Vec::with_capacity(64),
+            line_no: 0,
+            pending: None,
+        })
+    }
+
+    /// Returns the next consecutive block of lines sharing the same 5 character prefix.
+    /// The dump is expected to be ordered by hash, otherwise a range may be returned multiple times.
+    pub async fn next_range(&mut self) -> io::Result<Option<HashList>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_until(b'\n', &mut self.line).await? == 0 {
+                return Ok(self
+                    .pending
+                    .take()
+                    .map(|(id, data)| range_list(id, data, self.format)));
+            }
+            self.line_no += 1;
+            let line = self.line.trim_ascii_end();
+            if line.is_empty() {
+                continue;
+            }
+            let (suffix, id) = match parse_range_prefix(line) {
+                Ok(x) => x,
+                Err(_) => {
+                    warn!("skipping malformed line {} in hash dump", self.line_no);
+                    continue;
+                }
+            };
+            match &mut self.pending {
+                Some((pending_id, data)) if *pending_id == id => {
+                    data.put_slice(b"\r\n");
+                    data.put_slice(suffix);
+                }
+                _ => {
+                    let mut data = BytesMut::with_capacity(64 * 1024);
+                    data.put_slice(suffix);
+                    if let Some((prev_id, prev_data)) = self.pending.replace((id, data)) {
+                        return Ok(Some(range_list(prev_id, prev_data, self.format)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn range_list(id: u32, data: BytesMut, format: ListFormat) -> HashList {
+    let data = data.freeze();
+    HashList {
+        id,
+        meta: ListMeta {
+            digest: Some(faster_hex::hex_string(&Sha1::digest(&data))),
+            size: data.len() as u64,
+            ..Default::default()
+        },
+        data,
+        format,
+        reject_partial: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_file;
+
+    #[tokio::test]
+    async fn splits_dump_into_ranges() {
+        let path =
+            std::env::temp_dir().join(format!("ipwned_import_test_{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "000000005AD76BD555C1D6D771DE417A4B87E4B4:10\r\n\
+             00000000A8DAE4228F821FB418F59826079BF368:4\r\n\
+             \r\n\
+             not a hash\r\n\
+             00001C5B4EA6CB3D9E88E3EDEF26D3A1C6CFB1F7:2\r\n",
+        )
+        .unwrap();
+        let mut reader = DumpReader::open(&path, ListFormat::Range).await.unwrap();
+        let first = reader.next_range().await.unwrap().unwrap();
+        let second = reader.next_range().await.unwrap().unwrap();
+        assert!(reader.next_range().await.unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first.id, 0);
+        assert_eq!(
+            &first.data[..],
+            b"0005AD76BD555C1D6D771DE417A4B87E4B4:10\r\n000A8DAE4228F821FB418F59826079BF368:4"
+        );
+        assert_eq!(first.meta.size, first.data.len() as u64);
+        assert_eq!(second.id, 1);
+        assert_eq!(second.format, ListFormat::Range);
+
+        let (rem, hashes) = parse_file(second.id, &second.data).unwrap();
+        assert!(rem.is_empty());
+        let mut expected = [0u8; 20];
+        faster_hex::hex_decode(b"00001C5B4EA6CB3D9E88E3EDEF26D3A1C6CFB1F7", &mut expected).unwrap();
+        assert_eq!(hashes, vec![expected.to_vec()]);
+    }
+}
@@ -0,0 +1,31 @@
+//! Download the haveibeenpwned.com password hash lists and store them in a compact quotient filter for local lookups.
+//!
+//! [`PwnedFilter`] queries a filter file created by `ipwned-builder`, [`binary_protocol::Client`] queries a running
+//! `ipwned-server` over its binary protocol. The remaining modules are the building blocks of the builder and server
+//! binaries and require the default `tools` feature, without it the crate only depends on what is needed for queries
+//! and builds for `wasm32`.
+
+#[cfg(feature = "tools")]
+pub mod allowlist;
+pub mod binary_protocol;
+#[cfg(feature = "tools")]
+pub mod downloader;
+#[cfg(feature = "tools")]
+pub mod filter_builder;
+#[cfg(feature = "tools")]
+pub mod import;
+#[cfg(feature = "tools")]
+pub mod logging;
+#[cfg(feature = "tools")]
+pub mod misc;
+#[cfg(feature = "tools")]
+pub mod parse;
+pub mod pwned_filter;
+#[cfg(feature = "tools")]
+pub mod statedb;
+
+#[cfg(feature = "tools")]
+pub use filter_builder::FilterBuilder;
+pub use pwned_filter::{FilterInfo, PwnedFilter};
+#[cfg(feature = "tools")]
+pub use statedb::StateDatabase;
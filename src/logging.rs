@@ -0,0 +1,92 @@
+use chrono::Local;
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use serde_json::{Map, json};
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expected text or json", s)),
+        }
+    }
+}
+
+/// Writes one JSON object per log record to stderr, including structured key-values of the record.
+pub struct JsonLogger {
+    level: LevelFilter,
+    /// target prefixes whose records below warning level are dropped
+    quiet_targets: Vec<&'static str>,
+}
+
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = serde_json::to_value(value).map_err(Error::boxed)?;
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl JsonLogger {
+    pub fn new(level: LevelFilter) -> Box<JsonLogger> {
+        Box::new(JsonLogger {
+            level,
+            quiet_targets: Vec::new(),
+        })
+    }
+
+    /// drops info and lower records of targets starting with `target`, warnings and errors are still logged
+    pub fn quiet_target(mut self: Box<Self>, target: &'static str) -> Box<JsonLogger> {
+        self.quiet_targets.push(target);
+        self
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && (metadata.level() <= Level::Warn
+                || !self
+                    .quiet_targets
+                    .iter()
+                    .any(|x| metadata.target().starts_with(x)))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut entry = Map::new();
+        entry.insert("ts".into(), json!(Local::now().to_rfc3339()));
+        entry.insert("level".into(), json!(record.level().as_str()));
+        entry.insert("target".into(), json!(record.target()));
+        entry.insert("msg".into(), json!(record.args().to_string()));
+        let _ = record.key_values().visit(&mut JsonFields(&mut entry));
+        write_json_line(&entry);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// writes a single JSON object as one line to stderr
+pub fn write_json_line<T: Serialize>(entry: &T) {
+    let mut stderr = std::io::stderr().lock();
+    let _ = serde_json::to_writer(&mut stderr, entry);
+    let _ = stderr.write_all(b"\n");
+}
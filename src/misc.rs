@@ -8,8 +8,11 @@ pub const MAX_COUNT: u32 = 16_u32.pow(5) - 1;
 pub enum DownloadStatus {
     Skipped(),
     NotOutdated(),
+    /// the downloaded list has the same digest as the stored one
+    Unchanged(),
     InternalError(),
-    HTTPError(DownloadError),
+    /// download of the range with the given id failed
+    HTTPError(u32, DownloadError),
 }
 
 #[derive(Clone)]
@@ -1,35 +1,51 @@
+use crate::pwned_filter::ntlm;
 use bytes::{BufMut, Bytes};
 use faster_hex::hex_decode_unchecked;
 use nom::bytes::complete::{tag, take_while_m_n};
 use nom::character::complete::{digit1, line_ending};
+use nom::combinator::opt;
 use nom::multi::separated_list0;
-use nom::sequence::separated_pair;
+use nom::sequence::{preceded, separated_pair, terminated};
 use nom::{AsChar, IResult, Parser};
+use sha1::{Digest, Sha1};
 use std::str::from_utf8_unchecked;
 
-fn parse_hash(s: &[u8]) -> IResult<&[u8], &[u8]> {
-    take_while_m_n(35, 35, AsChar::is_hex_digit)(s)
-}
-
-fn parse_line(s: &[u8]) -> IResult<&[u8], &[u8]> {
+fn parse_line(suffix_len: usize) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> {
     // discards count
-    let (rem, (hash, _)) = separated_pair(parse_hash, tag(":"), digit1).parse(s)?;
-    Ok((rem, hash))
+    move |s| {
+        let (rem, (hash, _)) = separated_pair(
+            take_while_m_n(suffix_len, suffix_len, AsChar::is_hex_digit),
+            tag(":"),
+            digit1,
+        )
+        .parse(s)?;
+        Ok((rem, hash))
+    }
 }
 
+/// parses a SHA1 range as returned by the range API, 35 character suffixes of the hashes with a count
 pub fn parse_file(prefix: u32, s: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
+    parse_range(prefix, s, 20)
+}
+
+/// parses an NTLM range as returned by the range API with `mode=ntlm`, 27 character suffixes of the hashes with a count
+pub fn parse_ntlm_file(prefix: u32, s: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
+    parse_range(prefix, s, 16)
+}
+
+fn parse_range(prefix: u32, s: &[u8], hash_len: usize) -> IResult<&[u8], Vec<Bytes>> {
     let mut base_hash = Vec::with_capacity(3);
     base_hash.put_u16((prefix >> 4) as u16);
     base_hash.put_u8((prefix as u8) << 4);
 
-    let (rem, hex_hashes) = separated_list0(line_ending, parse_line).parse(s)?;
+    let (rem, hex_hashes) = separated_list0(line_ending, parse_line(hash_len * 2 - 5)).parse(s)?;
     let mut hashes: Vec<Bytes> = Vec::with_capacity(hex_hashes.len());
 
     for hex in hex_hashes {
-        let mut hash = vec![0; 20];
+        let mut hash = vec![0; hash_len];
         hash[..3].copy_from_slice(&base_hash);
 
-        // guaranteed to be [:xdigit:] because of is_hex_digit call in parse_hash
+        // guaranteed to be [:xdigit:] because of is_hex_digit call in parse_line
         let byte3 = unsafe { from_utf8_unchecked(&hex[0..1]) };
         hash[2] |= u8::from_str_radix(byte3, 16).unwrap();
 
@@ -38,3 +54,118 @@ pub fn parse_file(prefix: u32, s: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
     }
     Ok((rem, hashes))
 }
+
+pub fn parse_range_prefix(s: &[u8]) -> IResult<&[u8], u32> {
+    let (rem, hex) = take_while_m_n(5, 5, AsChar::is_hex_digit)(s)?;
+    // guaranteed to be [:xdigit:] because of is_hex_digit call above
+    let prefix = u32::from_str_radix(unsafe { from_utf8_unchecked(hex) }, 16).unwrap();
+    Ok((rem, prefix))
+}
+
+fn parse_full_line(s: &[u8]) -> IResult<&[u8], &[u8]> {
+    // count is optional and discarded
+    terminated(
+        take_while_m_n(40, 40, AsChar::is_hex_digit),
+        opt(preceded(tag(":"), digit1)),
+    )
+    .parse(s)
+}
+
+/// parses full SHA1 hashes, one per line. like `hash_plaintext_list` lines are trimmed and empty lines skipped, the
+/// remainder starts at the first line that is not a hash
+pub fn parse_sha1_list(s: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
+    let mut hashes = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let (line, next) = match rest.iter().position(|c| *c == b'\n') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, &rest[rest.len()..]),
+        };
+        let line = line.trim_ascii();
+        if !line.is_empty() {
+            match parse_full_line(line) {
+                Ok((&[], hex)) => {
+                    let mut hash = vec![0; 20];
+                    // guaranteed to be [:xdigit:] because of is_hex_digit call in parse_full_line
+                    hex_decode_unchecked(hex, &mut hash);
+                    hashes.push(Bytes::from(hash));
+                }
+                _ => return Ok((rest, hashes)),
+            }
+        }
+        rest = next;
+    }
+    Ok((rest, hashes))
+}
+
+pub fn hash_plaintext_list(s: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
+    let hashes = s
+        .split(|c| *c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(|line| Bytes::from(Sha1::digest(line).to_vec()))
+        .collect();
+    Ok((&s[s.len()..], hashes))
+}
+
+/// like `hash_plaintext_list`, but with NTLM hashes. Lines which are not valid UTF-8 are hashed lossy
+pub fn hash_plaintext_ntlm_list(s: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
+    let hashes = s
+        .split(|c| *c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(|line| Bytes::from(ntlm(&String::from_utf8_lossy(line)).to_vec()))
+        .collect();
+    Ok((&s[s.len()..], hashes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_prefix() {
+        assert_eq!(parse_range_prefix(b"21BD1"), Ok((&b""[..], 0x21bd1)));
+        assert_eq!(
+            parse_range_prefix(b"fffff0123:1"),
+            Ok((&b"0123:1"[..], 0xfffff))
+        );
+        assert!(parse_range_prefix(b"21BD").is_err());
+        assert!(parse_range_prefix(b"21BDX").is_err());
+    }
+
+    #[test]
+    fn sha1_list() {
+        let hash = |hex: &str| {
+            let mut hash = vec![0; 20];
+            faster_hex::hex_decode(hex.as_bytes(), &mut hash).unwrap();
+            Bytes::from(hash)
+        };
+        let input = b"A94A8FE5CCB19BA61C4C0873D391E987982FBBD3:12\r\n\r\n  \n\
+                      0000000000000000000000000000000000000000 \r\n\
+                      \t7c4a8d09ca3762af61e59520943dc26494f8941b\n";
+        let (rem, hashes) = parse_sha1_list(input).unwrap();
+        assert!(rem.is_empty());
+        assert_eq!(
+            hashes,
+            vec![
+                hash("a94a8fe5ccb19ba61c4c0873d391e987982fbbd3"),
+                hash("0000000000000000000000000000000000000000"),
+                hash("7c4a8d09ca3762af61e59520943dc26494f8941b"),
+            ]
+        );
+        let (rem, hashes) = parse_sha1_list(b"").unwrap();
+        assert!(rem.is_empty() && hashes.is_empty());
+    }
+
+    #[test]
+    fn sha1_list_remainder() {
+        let input = b"0000000000000000000000000000000000000000\n\nnot a hash\n\
+                      7c4a8d09ca3762af61e59520943dc26494f8941b\n";
+        let (rem, hashes) = parse_sha1_list(input).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(rem, &input[42..]);
+        let (rem, hashes) = parse_sha1_list(b"0000000000000000000000000000000000000000:x").unwrap();
+        assert_eq!((rem.len(), hashes.len()), (42, 0));
+    }
+}
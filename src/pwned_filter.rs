@@ -0,0 +1,143 @@
+use md4::Md4;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Hash function of the passwords in a filter, a filter only contains hashes of one type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashType {
+    Sha1,
+    Ntlm,
+}
+
+impl HashType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashType::Sha1 => "sha1",
+            HashType::Ntlm => "ntlm",
+        }
+    }
+
+    /// length of a hash in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            HashType::Sha1 => 20,
+            HashType::Ntlm => 16,
+        }
+    }
+}
+
+impl FromStr for HashType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(HashType::Sha1),
+            "ntlm" => Ok(HashType::Ntlm),
+            _ => Err(format!("unknown hash type {}, expected sha1 or ntlm", s)),
+        }
+    }
+}
+
+/// NTLM hash of a password, MD4 of its UTF-16LE encoding
+pub fn ntlm(password: &str) -> [u8; 16] {
+    let mut hasher = Md4::new();
+    for c in password.encode_utf16() {
+        hasher.update(c.to_le_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// size and error rate of a [`PwnedFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FilterInfo {
+    pub entries: u64,
+    pub capacity: u64,
+    pub max_error_rate: f64,
+    pub current_error_rate: f64,
+    /// size of the filter in memory in bytes, about the size of the filter file
+    pub memory_usage: usize,
+}
+
+/// A read-only filter of compromised password hashes, as written by `ipwned-builder`.
+///
+/// Lookups may return false positives at the filter's error rate, but never false negatives.
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct PwnedFilter {
+    filter: qfilter::Filter,
+}
+
+impl PwnedFilter {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PwnedFilter> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> io::Result<PwnedFilter> {
+        let filter = ciborium::from_reader(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(PwnedFilter { filter })
+    }
+
+    /// whether the SHA1 hash (raw bytes, not hex) is in the filter
+    pub fn contains(&self, sha1: &[u8; 20]) -> bool {
+        self.filter.contains(&sha1[..])
+    }
+
+    /// whether the hex encoded SHA1 hash is in the filter, `None` if it is not 40 hex characters
+    pub fn contains_hex(&self, hex: &str) -> Option<bool> {
+        let mut hash = [0u8; 20];
+        if hex.len() != 40 || faster_hex::hex_decode(hex.as_bytes(), &mut hash).is_err() {
+            return None;
+        }
+        Some(self.contains(&hash))
+    }
+
+    /// whether the SHA1 hash of the password is in the filter, HIBP hashes UTF-8 encoded passwords
+    pub fn contains_password<P: AsRef<[u8]>>(&self, password: P) -> bool {
+        let hash: [u8; 20] = Sha1::digest(password.as_ref()).into();
+        self.contains(&hash)
+    }
+
+    /// copies the filter into a new one with a higher false positive rate, which is smaller, e.g. for shipping to
+    /// clients. fails if `max_error_rate` is lower than the error rate of this filter
+    pub fn with_error_rate(&self, max_error_rate: f64) -> io::Result<PwnedFilter> {
+        let invalid = |e: qfilter::Error| {
+            let message = match e {
+                qfilter::Error::IncompatibleFingerprintSize => String::from(
+                    "the error rate needs more fingerprint bits than the filter has, use a higher error rate",
+                ),
+                e => format!("unable to copy filter: {}", e),
+            };
+            io::Error::new(io::ErrorKind::InvalidInput, message)
+        };
+        let mut filter =
+            qfilter::Filter::new(self.filter.len().max(1), max_error_rate).map_err(invalid)?;
+        filter.merge(false, &self.filter).map_err(invalid)?;
+        Ok(PwnedFilter { filter })
+    }
+
+    /// whether the raw 16 byte NTLM hash is in the filter, only useful for filters built with `--hash-type ntlm`
+    pub fn contains_ntlm(&self, ntlm: &[u8; 16]) -> bool {
+        self.filter.contains(&ntlm[..])
+    }
+
+    /// whether the NTLM hash of the password is in the filter, only useful for filters built with `--hash-type ntlm`
+    pub fn contains_ntlm_password(&self, password: &str) -> bool {
+        self.contains_ntlm(&ntlm(password))
+    }
+
+    /// size and error rate of the filter
+    pub fn info(&self) -> FilterInfo {
+        FilterInfo {
+            entries: self.filter.len(),
+            capacity: self.filter.capacity(),
+            max_error_rate: self.filter.max_error_ratio(),
+            current_error_rate: self.filter.current_error_ratio(),
+            memory_usage: self.filter.memory_usage(),
+        }
+    }
+}
@@ -1,12 +1,107 @@
-use rusqlite::MAIN_DB;
+use rusqlite::{MAIN_DB, OpenFlags};
 use std::path::PathBuf;
-use tokio_rusqlite;
 use tokio_rusqlite::{Connection, OptionalExtension, Result};
 
+/// schema migrations, the database's user_version is the number of applied migrations
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema, the tables may already exist in databases created before versioning
+    "CREATE TABLE IF NOT EXISTS document (\
+        id   INTEGER PRIMARY KEY,\
+        etag TEXT,\
+        last_update DATETIME DEFAULT CURRENT_TIMESTAMP\
+    );\
+    CREATE TABLE IF NOT EXISTS source (\
+        id     INTEGER PRIMARY KEY,\
+        path   TEXT NOT NULL UNIQUE,\
+        kind   TEXT NOT NULL,\
+        digest TEXT,\
+        hashes INTEGER,\
+        last_update DATETIME DEFAULT CURRENT_TIMESTAMP\
+    );\
+    CREATE TABLE IF NOT EXISTS allowlist (\
+        hash      BLOB PRIMARY KEY,\
+        in_filter INTEGER NOT NULL\
+    );\
+    CREATE TABLE IF NOT EXISTS failure (\
+        id          INTEGER PRIMARY KEY,\
+        status_code INTEGER,\
+        last_error  TEXT NOT NULL,\
+        count       INTEGER NOT NULL,\
+        last_attempt DATETIME DEFAULT CURRENT_TIMESTAMP\
+    );",
+    // 2: inventory of each range's content
+    "ALTER TABLE document ADD COLUMN hashes INTEGER;\
+    ALTER TABLE document ADD COLUMN size INTEGER;\
+    ALTER TABLE document ADD COLUMN digest TEXT;\
+    ALTER TABLE document ADD COLUMN status INTEGER;\
+    ALTER TABLE document ADD COLUMN last_modified TEXT;",
+];
+
 pub struct State {
     pub id: u32,
     pub etag: Option<String>,
     pub last_update: String, //PrimitiveDateTime,
+    /// number of hashes in the range
+    pub hashes: Option<u32>,
+    /// size of the downloaded list in bytes
+    pub size: Option<u64>,
+    /// hex encoded SHA1 digest of the downloaded list
+    pub digest: Option<String>,
+    /// HTTP status of the last download attempt
+    pub status: Option<u16>,
+    /// Last-Modified header of the last downloaded list
+    pub last_modified: Option<String>,
+}
+
+/// content of a range after it was added to the filter
+pub struct RangeUpdate {
+    pub etag: Option<String>,
+    pub hashes: u32,
+    pub size: u64,
+    pub digest: Option<String>,
+    pub status: Option<u16>,
+    pub last_modified: Option<String>,
+}
+
+pub struct Source {
+    pub id: u32,
+    pub path: String,
+    pub kind: String,
+    pub digest: Option<String>,
+    pub hashes: Option<u32>,
+    pub last_update: String,
+}
+
+/// aggregate over the fetched ranges in an id interval
+pub struct RangeSummary {
+    pub fetched: u32,
+    pub hashes: u64,
+    pub size: u64,
+    pub oldest: Option<String>,
+    pub newest: Option<String>,
+}
+
+/// a range whose last download attempts failed
+pub struct Failure {
+    pub id: u32,
+    pub status_code: Option<u16>,
+    pub last_error: String,
+    /// number of consecutive failed runs
+    pub count: u32,
+    pub last_attempt: String,
+}
+
+fn state_from_row(r: &rusqlite::Row) -> rusqlite::Result<State> {
+    Ok(State {
+        id: r.get(0)?,
+        etag: r.get(1)?,
+        last_update: r.get(2)?,
+        hashes: r.get(3)?,
+        size: r.get(4)?,
+        digest: r.get(5)?,
+        status: r.get(6)?,
+        last_modified: r.get(7)?,
+    })
 }
 
 pub struct StateDatabase {
@@ -16,11 +111,17 @@ pub struct StateDatabase {
 impl StateDatabase {
     pub async fn open(path: &PathBuf) -> Result<StateDatabase> {
         let conn = Connection::open(path).await?;
-        let db = StateDatabase { conn: conn };
-        db.create().await?;
+        let db = StateDatabase { conn };
+        db.migrate().await?;
         Ok(db)
     }
 
+    /// opens an existing database without creating or changing any tables
+    pub async fn open_readonly(path: &PathBuf) -> Result<StateDatabase> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).await?;
+        Ok(StateDatabase { conn })
+    }
+
     pub async fn close(self) -> Result<()> {
         self.conn.close().await
     }
@@ -28,31 +129,275 @@ impl StateDatabase {
     pub async fn fetch(&self, id: u32) -> Result<Option<State>> {
         self.conn
             .call(move |conn| {
-                let mut stmt = conn.prepare("SELECT * FROM document WHERE id = ?")?;
-                Ok(stmt.query_row([id], |r| {
-                    Ok(Some(State {
-                        id: r.get(0)?,
-                        etag: r.get(1)?,
-                        last_update: r.get(2)?,
-                    }))
+                let mut stmt = conn.prepare(
+                    "SELECT id, etag, last_update, hashes, size, digest, status, last_modified \
+                    FROM document WHERE id = ?",
+                )?;
+                Ok(stmt.query_row([id], state_from_row).optional()?)
+            })
+            .await
+    }
+
+    /// returns the state of all fetched ranges between start and end (inclusive)
+    pub async fn fetch_states(&self, start: u32, end: u32) -> Result<Vec<State>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, etag, last_update, hashes, size, digest, status, last_modified \
+                    FROM document WHERE id BETWEEN ?1 AND ?2",
+                )?;
+                let rows = stmt.query_map([start, end], state_from_row)?;
+                Ok(rows.collect::<rusqlite::Result<Vec<State>>>()?)
+            })
+            .await
+    }
+
+    /// returns the ids between start and end (inclusive) which have been fetched before
+    pub async fn fetch_ids(&self, start: u32, end: u32) -> Result<Vec<u32>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT id FROM document WHERE id BETWEEN ?1 AND ?2")?;
+                let rows = stmt.query_map([start, end], |r| r.get(0))?;
+                Ok(rows.collect::<rusqlite::Result<Vec<u32>>>()?)
+            })
+            .await
+    }
+
+    pub async fn summarize_ranges(&self, start: u32, end: u32) -> Result<RangeSummary> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT COUNT(*), COALESCE(SUM(hashes), 0), COALESCE(SUM(size), 0), \
+                    MIN(last_update), MAX(last_update) FROM document WHERE id BETWEEN ?1 AND ?2",
+                )?;
+                Ok(stmt.query_row([start, end], |r| {
+                    Ok(RangeSummary {
+                        fetched: r.get(0)?,
+                        hashes: r.get(1)?,
+                        size: r.get(2)?,
+                        oldest: r.get(3)?,
+                        newest: r.get(4)?,
+                    })
                 })?)
             })
             .await
     }
 
-    pub async fn update(&self, id: u32, etag: Option<String>) -> bool {
+    /// returns the ids between start and end (inclusive) last updated before the given UTC time
+    /// (formatted as %Y-%m-%d %H:%M:%S), oldest first
+    pub async fn fetch_outdated(&self, start: u32, end: u32, before: String) -> Result<Vec<u32>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id FROM document WHERE id BETWEEN ?1 AND ?2 AND last_update < ?3 \
+                    ORDER BY last_update",
+                )?;
+                let rows = stmt.query_map((start, end, before), |r| r.get(0))?;
+                Ok(rows.collect::<rusqlite::Result<Vec<u32>>>()?)
+            })
+            .await
+    }
+
+    /// records a range that was downloaded and added to the filter, which also clears previous failures
+    pub async fn update_range(&self, id: u32, update: RangeUpdate) -> bool {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "INSERT INTO document(id, etag, last_update, hashes, size, digest, status, last_modified) \
+                    VALUES(?1, ?2, CURRENT_TIMESTAMP, ?3, ?4, ?5, ?6, ?7) \
+                    ON CONFLICT(id) DO UPDATE SET etag = ?2, last_update = CURRENT_TIMESTAMP, hashes = ?3, \
+                    size = ?4, digest = ?5, status = ?6, last_modified = ?7",
+                )?;
+                stmt.execute((
+                    id,
+                    update.etag,
+                    update.hashes,
+                    update.size,
+                    update.digest,
+                    update.status,
+                    update.last_modified,
+                ))?;
+                Ok(conn.execute("DELETE FROM failure WHERE id = ?", [id])?)
+            })
+            .await
+            .is_ok()
+    }
+
+    /// records a download of a range whose content did not change, which resets its age and clears previous failures
+    pub async fn mark_unchanged(&self, id: u32, etag: Option<String>, status: u16) -> bool {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "INSERT INTO document(id, etag, last_update, status) VALUES(?1, ?2, CURRENT_TIMESTAMP, ?3) \
+                    ON CONFLICT(id) DO UPDATE SET etag = ?2, last_update = CURRENT_TIMESTAMP, status = ?3",
+                )?;
+                stmt.execute((id, etag, status))?;
+                Ok(conn.execute("DELETE FROM failure WHERE id = ?", [id])?)
+            })
+            .await
+            .is_ok()
+    }
+
+    pub async fn record_failure(&self, id: u32, status_code: Option<u16>, error: String) -> bool {
         self.conn
             .call(move |conn| {
                 let mut stmt = conn.prepare(
-                    "INSERT INTO document(id, etag, last_update) VALUES(?1, ?2, CURRENT_TIMESTAMP) \
-                    ON CONFLICT(id) DO UPDATE SET etag = ?2, last_update = CURRENT_TIMESTAMP",
+                    "INSERT INTO failure(id, status_code, last_error, count, last_attempt) \
+                    VALUES(?1, ?2, ?3, 1, CURRENT_TIMESTAMP) \
+                    ON CONFLICT(id) DO UPDATE SET status_code = ?2, last_error = ?3, count = count + 1, \
+                    last_attempt = CURRENT_TIMESTAMP",
                 )?;
-                Ok(stmt.execute((id, etag))?)
+                stmt.execute((id, status_code, error))?;
+                Ok(conn.execute(
+                    "UPDATE document SET status = ?2 WHERE id = ?1",
+                    (id, status_code),
+                )?)
             })
             .await
             .is_ok()
     }
 
+    /// returns the failed ranges between start and end (inclusive), oldest attempt first
+    pub async fn fetch_failures(&self, start: u32, end: u32) -> Result<Vec<Failure>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, status_code, last_error, count, last_attempt FROM failure \
+                    WHERE id BETWEEN ?1 AND ?2 ORDER BY last_attempt",
+                )?;
+                let rows = stmt.query_map([start, end], |r| {
+                    Ok(Failure {
+                        id: r.get(0)?,
+                        status_code: r.get(1)?,
+                        last_error: r.get(2)?,
+                        count: r.get(3)?,
+                        last_attempt: r.get(4)?,
+                    })
+                })?;
+                Ok(rows.collect::<rusqlite::Result<Vec<Failure>>>()?)
+            })
+            .await
+    }
+
+    pub async fn fetch_source(&self, path: String) -> Result<Option<Source>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, path, kind, digest, hashes, last_update FROM source WHERE path = ?",
+                )?;
+                Ok(stmt
+                    .query_row([path], |r| {
+                        Ok(Source {
+                            id: r.get(0)?,
+                            path: r.get(1)?,
+                            kind: r.get(2)?,
+                            digest: r.get(3)?,
+                            hashes: r.get(4)?,
+                            last_update: r.get(5)?,
+                        })
+                    })
+                    .optional()?)
+            })
+            .await
+    }
+
+    pub async fn fetch_sources(&self) -> Result<Vec<Source>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, path, kind, digest, hashes, last_update FROM source ORDER BY id",
+                )?;
+                let rows = stmt.query_map([], |r| {
+                    Ok(Source {
+                        id: r.get(0)?,
+                        path: r.get(1)?,
+                        kind: r.get(2)?,
+                        digest: r.get(3)?,
+                        hashes: r.get(4)?,
+                        last_update: r.get(5)?,
+                    })
+                })?;
+                Ok(rows.collect::<rusqlite::Result<Vec<Source>>>()?)
+            })
+            .await
+    }
+
+    /// returns the id of the source with the given path, creating it if necessary
+    pub async fn register_source(&self, path: String, kind: String) -> Result<u32> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "INSERT INTO source(path, kind) VALUES(?1, ?2) \
+                    ON CONFLICT(path) DO UPDATE SET kind = ?2 RETURNING id",
+                )?;
+                Ok(stmt.query_row((path, kind), |r| r.get(0))?)
+            })
+            .await
+    }
+
+    pub async fn update_source(&self, id: u32, digest: Option<String>, hashes: u32) -> bool {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "UPDATE source SET digest = ?2, hashes = ?3, last_update = CURRENT_TIMESTAMP \
+                    WHERE id = ?1",
+                )?;
+                Ok(stmt.execute((id, digest, hashes))?)
+            })
+            .await
+            .is_ok()
+    }
+
+    /// returns all allowlisted hashes that were checked against the hash lists,
+    /// and whether they were found in a list and therefore removed from the filter
+    pub async fn fetch_allowlisted(&self) -> Result<Vec<(Vec<u8>, bool)>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare("SELECT hash, in_filter FROM allowlist")?;
+                let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+                Ok(rows.collect::<rusqlite::Result<Vec<(Vec<u8>, bool)>>>()?)
+            })
+            .await
+    }
+
+    pub async fn set_allowlisted(&self, hash: Vec<u8>, in_filter: bool) -> bool {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "INSERT INTO allowlist(hash, in_filter) VALUES(?1, ?2) \
+                    ON CONFLICT(hash) DO UPDATE SET in_filter = ?2",
+                )?;
+                Ok(stmt.execute((hash, in_filter))?)
+            })
+            .await
+            .is_ok()
+    }
+
+    pub async fn remove_allowlisted(&self, hash: Vec<u8>) -> bool {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare("DELETE FROM allowlist WHERE hash = ?")?;
+                Ok(stmt.execute([hash])?)
+            })
+            .await
+            .is_ok()
+    }
+
+    /// whether all migrations were applied, databases opened read-only are not migrated
+    pub async fn is_current(&self) -> bool {
+        self.conn
+            .call(|conn| {
+                Ok(
+                    conn.query_row("SELECT user_version FROM pragma_user_version", [], |r| {
+                        r.get::<_, usize>(0)
+                    })?,
+                )
+            })
+            .await
+            .is_ok_and(|x| x == MIGRATIONS.len())
+    }
+
     pub async fn is_readonly(&self) -> bool {
         self.conn
             .call(move |conn| Ok(conn.is_readonly(MAIN_DB)?))
@@ -60,33 +405,102 @@ impl StateDatabase {
             .unwrap_or(true)
     }
 
-    async fn create(&self) -> Result<()> {
-        let result = self
-            .conn
+    /// applies all migrations newer than the database's schema version
+    async fn migrate(&self) -> Result<()> {
+        self.conn
             .call(|conn| {
-                Ok(conn
-                    .query_row(
-                        "SELECT 1 FROM sqlite_master WHERE type='table' AND name='document'",
-                        [],
-                        |r| r.get::<_, u8>(0),
-                    )
-                    .optional()?)
+                let version: usize =
+                    conn.query_row("SELECT user_version FROM pragma_user_version", [], |r| {
+                        r.get(0)
+                    })?;
+                if version > MIGRATIONS.len() {
+                    return Err(tokio_rusqlite::Error::Other(
+                        format!(
+                            "state database schema version {} is newer than the supported version {}",
+                            version,
+                            MIGRATIONS.len()
+                        )
+                        .into(),
+                    ));
+                }
+                for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                    let tx = conn.transaction()?;
+                    tx.execute_batch(migration)?;
+                    tx.pragma_update(None, "user_version", i + 1)?;
+                    tx.commit()?;
+                }
+                Ok(())
             })
-            .await?;
-        if result.is_none() {
-            self.conn
-                .call(|conn| {
-                    Ok(conn.execute(
-                        "CREATE TABLE document (\
-                            id   INTEGER PRIMARY KEY,\
-                            etag TEXT,\
-                            last_update DATETIME DEFAULT CURRENT_TIMESTAMP\
-                        )",
-                        (),
-                    )?)
-                })
-                .await?;
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "ipwned_statedb_test_{}_{}.sqlite",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn migrates_baseline_database() {
+        let path = temp_path("baseline");
+        {
+            // schema of databases created before versioning
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE document (\
+                    id   INTEGER PRIMARY KEY,\
+                    etag TEXT,\
+                    last_update DATETIME DEFAULT CURRENT_TIMESTAMP\
+                );\
+                INSERT INTO document (id, etag) VALUES (42, 'abc');",
+            )
+            .unwrap();
+        }
+
+        let db = StateDatabase::open(&path).await.unwrap();
+        assert!(db.is_current().await);
+        let state = db.fetch(42).await.unwrap().unwrap();
+        assert_eq!(state.etag.as_deref(), Some("abc"));
+        assert_eq!(state.hashes, None);
+        let update = RangeUpdate {
+            etag: Some(String::from("def")),
+            hashes: 3,
+            size: 120,
+            digest: Some(String::from("00")),
+            status: Some(200),
+            last_modified: None,
+        };
+        assert!(db.update_range(42, update).await);
+        db.close().await.unwrap();
+
+        // migrations are only applied once
+        let db = StateDatabase::open(&path).await.unwrap();
+        let state = db.fetch(42).await.unwrap().unwrap();
+        assert_eq!(state.etag.as_deref(), Some("def"));
+        assert_eq!(state.hashes, Some(3));
+        assert_eq!(state.size, Some(120));
+        db.close().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_newer_schema() {
+        let path = temp_path("newer");
+        {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+                .unwrap();
         }
-        Ok(())
+        assert!(StateDatabase::open(&path).await.is_err());
+        std::fs::remove_file(&path).unwrap();
     }
 }
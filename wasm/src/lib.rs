@@ -0,0 +1,122 @@
+//! JavaScript bindings for querying a filter in the browser or in edge workers, build with
+//! `wasm-pack build wasm --target web`.
+//!
+//! The filter is passed in as bytes, e.g. fetched from a server, since there is no file system. A full filter does
+//! not fit into the 4 GiB of a wasm32 memory, use `ipwned-builder export-client` to create a smaller one.
+
+use wasm_bindgen::prelude::*;
+
+/// A filter loaded from the bytes of a filter file. Lookups may return false positives at the filter's error rate,
+/// but never false negatives.
+#[wasm_bindgen(js_name = PwnedFilter)]
+pub struct Filter {
+    filter: ipwned_localdb::PwnedFilter,
+}
+
+impl Filter {
+    /// `new` without the conversion to a JavaScript error, which is only possible on wasm32
+    fn from_bytes(data: &[u8]) -> std::io::Result<Filter> {
+        let filter = ipwned_localdb::PwnedFilter::from_reader(data)?;
+        Ok(Filter { filter })
+    }
+}
+
+#[wasm_bindgen(js_class = PwnedFilter)]
+impl Filter {
+    /// reads a filter from the contents of a filter file
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Result<Filter, JsError> {
+        Ok(Filter::from_bytes(data)?)
+    }
+
+    /// whether the raw 20 byte SHA1 hash is in the filter
+    #[wasm_bindgen(js_name = containsSha1)]
+    pub fn contains_sha1(&self, sha1: &[u8]) -> Result<bool, JsError> {
+        let sha1 = <&[u8; 20]>::try_from(sha1)
+            .map_err(|_| JsError::new(&format!("expected 20 bytes, got {}", sha1.len())))?;
+        Ok(self.filter.contains(sha1))
+    }
+
+    /// whether the SHA1 hash given as 40 hex characters is in the filter
+    #[wasm_bindgen(js_name = containsHex)]
+    pub fn contains_hex(&self, hex: &str) -> Result<bool, JsError> {
+        self.filter
+            .contains_hex(hex)
+            .ok_or_else(|| JsError::new("not a hex encoded SHA1 hash"))
+    }
+
+    /// whether the SHA1 hash of the UTF-8 encoded password is in the filter
+    #[wasm_bindgen(js_name = containsPassword)]
+    pub fn contains_password(&self, password: &str) -> bool {
+        self.filter.contains_password(password)
+    }
+
+    /// number of hashes in the filter
+    #[wasm_bindgen(getter)]
+    pub fn entries(&self) -> f64 {
+        self.filter.info().entries as f64
+    }
+
+    /// maximum number of hashes the filter can hold
+    #[wasm_bindgen(getter)]
+    pub fn capacity(&self) -> f64 {
+        self.filter.info().capacity as f64
+    }
+
+    /// false positive rate the filter was created for
+    #[wasm_bindgen(getter, js_name = maxErrorRate)]
+    pub fn max_error_rate(&self) -> f64 {
+        self.filter.info().max_error_rate
+    }
+
+    /// false positive rate with the current number of entries
+    #[wasm_bindgen(getter, js_name = currentErrorRate)]
+    pub fn current_error_rate(&self) -> f64 {
+        self.filter.info().current_error_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+
+    /// contents of a filter file with the SHA1 hash of "password", copied with a higher error rate like
+    /// `export-client` does
+    fn filter_file() -> Vec<u8> {
+        let mut filter = qfilter::Filter::new(64, 1e-6).unwrap();
+        filter.insert(&Sha1::digest("password")[..]).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&filter, &mut cbor).unwrap();
+        let filter = ipwned_localdb::PwnedFilter::from_reader(&cbor[..])
+            .unwrap()
+            .with_error_rate(1e-4)
+            .unwrap();
+        let mut data = Vec::new();
+        ciborium::into_writer(&filter, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let filter = Filter::from_bytes(&filter_file()).unwrap();
+        assert!(filter.contains_password("password"));
+        assert!(!filter.contains_password("not pwned"));
+        assert!(filter.contains_sha1(&Sha1::digest("password")).unwrap());
+        assert!(!filter.contains_sha1(&[0; 20]).unwrap());
+        let hex = format!("{:x}", Sha1::digest("password"));
+        assert!(filter.contains_hex(&hex).unwrap());
+        assert_eq!(filter.entries(), 1.);
+        assert!(filter.max_error_rate() > 1e-6);
+        assert!(filter.capacity() >= filter.entries());
+    }
+
+    #[test]
+    fn invalid_filter() {
+        let data = filter_file();
+        for data in [&[][..], b"not a filter", &data[..data.len() / 2]] {
+            let error = Filter::from_bytes(data).err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}